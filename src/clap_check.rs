@@ -0,0 +1,235 @@
+use serde_json::{Map, Value};
+
+/// Arg keys understood by `clap_serde`. Anything else is most likely a typo,
+/// which `clap_serde` would only report without context.
+const ARG_KEYS: &[&str] = &[
+    "action",
+    "alias",
+    "aliases",
+    "allow_hyphen_values",
+    "allow_negative_numbers",
+    "conflicts_with",
+    "conflicts_with_all",
+    "default_missing_value",
+    "default_missing_values",
+    "default_value",
+    "default_value_if",
+    "default_value_ifs",
+    "default_values",
+    "display_order",
+    "env",
+    "exclusive",
+    "global",
+    "group",
+    "groups",
+    "help",
+    "help_heading",
+    "hide",
+    "hide_default_value",
+    "hide_env",
+    "hide_env_values",
+    "hide_long_help",
+    "hide_possible_values",
+    "hide_short_help",
+    "ignore_case",
+    "index",
+    "last",
+    "long",
+    "long_help",
+    "next_line_help",
+    "num_args",
+    "overrides_with",
+    "overrides_with_all",
+    "possible_values",
+    "require_equals",
+    "required",
+    "required_if_eq",
+    "required_if_eq_all",
+    "required_if_eq_any",
+    "required_unless_present",
+    "required_unless_present_all",
+    "required_unless_present_any",
+    "requires",
+    "requires_if",
+    "requires_ifs",
+    "short",
+    "short_alias",
+    "short_aliases",
+    "trailing_var_arg",
+    "value_delimiter",
+    "value_hint",
+    "value_name",
+    "value_names",
+    "value_parser",
+    "value_terminator",
+    "visible_alias",
+    "visible_aliases",
+    "visible_short_alias",
+    "visible_short_aliases",
+];
+
+/// Actions that make an arg a flag, which never takes values.
+const FLAG_ACTIONS: &[&str] = &["SetTrue", "SetFalse", "Count", "Help", "Version"];
+
+/// Checks the clap half of the config for common mistakes before it is
+/// handed to `clap_serde`, so they are reported with the command path and
+/// arg they belong to. Every problem is collected, prefixed with its path.
+/// Unknown arg keys only produce a warning, since `clap_serde` may know keys
+/// this list doesn't.
+pub fn clap_config_problems(v: &Value) -> Vec<String> {
+    let name = v.get("name").and_then(Value::as_str).unwrap_or("");
+    let mut problems = Vec::new();
+    check_command(v, name, &mut problems);
+    problems
+}
+
+fn check_command(v: &Value, path: &str, problems: &mut Vec<String>) {
+    let Value::Object(map) = v else {
+        problems.push(format!("{}: command must be an object", path));
+        return;
+    };
+
+    match map.get("args") {
+        None => {}
+        Some(Value::Array(args)) => {
+            for arg in args {
+                match single_entry(arg) {
+                    Some((arg_name, arg)) => {
+                        check_arg(arg, &format!("{}: arg '{}'", path, arg_name), problems)
+                    }
+                    None => problems.push(format!(
+                        "{}: every arg must be an object with exactly one key, its id",
+                        path
+                    )),
+                }
+            }
+        }
+        Some(_) => problems.push(format!("{}: 'args' must be an array", path)),
+    }
+
+    match map.get("subcommands") {
+        None => {}
+        Some(Value::Array(subcommands)) => {
+            for subcommand in subcommands {
+                match single_entry(subcommand) {
+                    Some((name, subcommand)) => {
+                        check_command(subcommand, &format!("{} {}", path, name), problems)
+                    }
+                    None => problems.push(format!(
+                        "{}: every subcommand must be an object with exactly one key, its name",
+                        path
+                    )),
+                }
+            }
+        }
+        Some(_) => problems.push(format!("{}: 'subcommands' must be an array", path)),
+    }
+}
+
+fn single_entry(v: &Value) -> Option<(&String, &Value)> {
+    match v {
+        Value::Object(map) if map.len() == 1 => map.iter().next(),
+        _ => None,
+    }
+}
+
+fn check_arg(v: &Value, path: &str, problems: &mut Vec<String>) {
+    let Value::Object(arg) = v else {
+        problems.push(format!("{}: must be an object", path));
+        return;
+    };
+
+    for key in arg.keys() {
+        if !ARG_KEYS.contains(&key.as_str()) {
+            eprintln!("warning: {}: unknown key '{}'", path, key);
+        }
+    }
+
+    if let Some(num_args) = arg.get("num_args") {
+        if let Err(error) = check_num_args(num_args) {
+            problems.push(format!("{}: {}", path, error));
+        }
+    }
+
+    let is_positional = !arg.contains_key("long") && !arg.contains_key("short");
+    let is_flag = arg
+        .get("action")
+        .and_then(Value::as_str)
+        .is_some_and(|action| FLAG_ACTIONS.contains(&action));
+
+    if is_positional && is_flag {
+        problems.push(format!(
+            "{}: a flag action needs 'long' or 'short', positionals always take values",
+            path
+        ));
+    }
+    if is_flag {
+        for key in [
+            "num_args",
+            "value_name",
+            "value_names",
+            "default_missing_value",
+        ] {
+            if arg.contains_key(key) {
+                problems.push(format!("{}: '{}' has no effect on a flag", path, key));
+            }
+        }
+    }
+    if !is_positional {
+        for key in ["index", "last", "trailing_var_arg"] {
+            if is_set(arg, key) {
+                problems.push(format!(
+                    "{}: '{}' only applies to positionals, remove 'long' and 'short'",
+                    path, key
+                ));
+            }
+        }
+    }
+}
+
+fn is_set(arg: &Map<String, Value>, key: &str) -> bool {
+    match arg.get(key) {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(_) => true,
+    }
+}
+
+/// `num_args` is either a count or a range like `1..`, `..=3` or `2..5`.
+fn check_num_args(v: &Value) -> anyhow::Result<()> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid num_args {}, expected a count or a range like \"1..\", \"..=3\" or \"2..5\"",
+            v
+        )
+    };
+
+    match v {
+        Value::Number(number) if number.is_u64() => Ok(()),
+        Value::String(range) => {
+            let (start, end, inclusive) = if let Some((start, end)) = range.split_once("..=") {
+                (start, end, true)
+            } else if let Some((start, end)) = range.split_once("..") {
+                (start, end, false)
+            } else {
+                range.parse::<usize>().map_err(|_| invalid())?;
+                return Ok(());
+            };
+
+            let parse = |bound: &str| -> anyhow::Result<Option<usize>> {
+                match bound.trim() {
+                    "" => Ok(None),
+                    bound => bound.parse().map(Some).map_err(|_| invalid()),
+                }
+            };
+            let start = parse(start)?.unwrap_or(0);
+            match parse(end)? {
+                None if inclusive => Err(invalid()),
+                Some(end) if end < start || (!inclusive && end == start) => {
+                    Err(anyhow::anyhow!("num_args range {} is empty", v))
+                }
+                _ => Ok(()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
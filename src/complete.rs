@@ -0,0 +1,65 @@
+use crate::{get_arg_config, get_subcommand_config, ArgConfig, CommandConfig};
+use anyhow::Context;
+use std::process::{Command as ProcCommand, Stdio};
+
+/// Runs the `complete_cmd` of the arg named `arg_name` of the command at the
+/// space separated `subcommand_path` and returns its stdout lines that start
+/// with `prefix`. Args inherited through `propagate_args` are found on the
+/// ancestor defining them.
+pub fn complete_value(
+    config: &CommandConfig,
+    subcommand_path: &str,
+    arg_name: &str,
+    prefix: &str,
+) -> anyhow::Result<String> {
+    let arg_config = find_arg_config(config, subcommand_path, arg_name)?;
+    let Some((program, complete_args)) = arg_config
+        .complete_cmd
+        .as_ref()
+        .and_then(|complete_cmd| complete_cmd.split_first())
+    else {
+        anyhow::bail!("Argument '{}' has no complete_cmd", arg_name)
+    };
+
+    let output = ProcCommand::new(program)
+        .args(complete_args)
+        .env("CLAP_BASH_ARG", arg_name)
+        .env("CLAP_BASH_PREFIX", prefix)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run complete_cmd of arg '{}'", arg_name))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "complete_cmd of arg '{}' failed with {}",
+            arg_name,
+            output.status
+        );
+    }
+
+    let mut candidates = String::new();
+    for candidate in String::from_utf8_lossy(&output.stdout).lines() {
+        if candidate.starts_with(prefix) {
+            candidates.push_str(candidate);
+            candidates.push('\n');
+        }
+    }
+    Ok(candidates)
+}
+
+fn find_arg_config<'a>(
+    config: &'a CommandConfig,
+    subcommand_path: &str,
+    arg_name: &str,
+) -> anyhow::Result<&'a ArgConfig> {
+    let mut config = config;
+    let mut ancestors = Vec::new();
+    for name in subcommand_path.split_whitespace() {
+        let Some(subconfig) = get_subcommand_config(config, name) else {
+            anyhow::bail!("No config found for subcommand '{}'", subcommand_path)
+        };
+        ancestors.push(config);
+        config = subconfig;
+    }
+    let (arg_config, _) = get_arg_config(config, &ancestors, subcommand_path, arg_name)?;
+    Ok(arg_config)
+}
@@ -0,0 +1,77 @@
+use crate::{quote_command, shell_quote};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// What `--assert-exec` and its companions expect the resolved command to be.
+pub struct ExecAssertion {
+    pub executable: PathBuf,
+    /// Only compared when `--assert-args` was given.
+    pub args: Option<Vec<String>>,
+    /// `KEY=VALUE` pairs that have to be in the resolved env.
+    pub env: Vec<String>,
+}
+
+/// Renders the command that would run as one shell quoted line and checks it
+/// against the assertion, if any. A mismatch is an error listing every
+/// expected and actual value that differs.
+pub fn dry_run(
+    executable: &Path,
+    raw_args: &[OsString],
+    env: &BTreeMap<String, String>,
+    assertion: Option<&ExecAssertion>,
+) -> anyhow::Result<String> {
+    let line = quote_command(executable, raw_args);
+    let executable = executable.to_string_lossy().into_owned();
+    let args: Vec<String> = raw_args
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+    if let Some(assertion) = assertion {
+        let mut diff = Vec::new();
+
+        let expected_executable = assertion.executable.to_string_lossy();
+        if expected_executable != executable {
+            diff.push(format!("- executable: {}", expected_executable));
+            diff.push(format!("+ executable: {}", executable));
+        }
+
+        if let Some(expected_args) = &assertion.args {
+            if expected_args != &args {
+                diff.push(format!("- args: {}", quote_all(expected_args)));
+                diff.push(format!("+ args: {}", quote_all(&args)));
+            }
+        }
+
+        for expected in &assertion.env {
+            let Some((key, value)) = expected.split_once('=') else {
+                anyhow::bail!("--assert-env expects KEY=VALUE, got '{}'", expected);
+            };
+            let actual = env.get(key);
+            if actual.map(String::as_str) != Some(value) {
+                diff.push(format!("- env: {}={}", key, value));
+                match actual {
+                    Some(actual) => diff.push(format!("+ env: {}={}", key, actual)),
+                    None => diff.push(format!("+ env: {} is unset", key)),
+                }
+            }
+        }
+
+        if !diff.is_empty() {
+            anyhow::bail!(
+                "The resolved command doesn't match the assertion:\n{}",
+                diff.join("\n")
+            );
+        }
+    }
+
+    Ok(format!("{}\n", line))
+}
+
+fn quote_all(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
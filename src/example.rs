@@ -0,0 +1,53 @@
+use clap::{Arg, Command};
+
+/// Prints one sample invocation per leaf command of the config. Required
+/// args are filled with `<VALUE_NAME>` placeholders, optional ones are left
+/// out, so every line is the shortest valid call of its command.
+pub fn example_invocations(command: &Command) -> String {
+    let mut lines = Vec::new();
+    collect_examples(command, Vec::new(), &mut lines);
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    output
+}
+
+fn collect_examples(command: &Command, mut words: Vec<String>, lines: &mut Vec<String>) {
+    words.push(command.get_name().to_string());
+
+    // Options first, positionals in definition order after them, like the help
+    // output lists them.
+    for arg in command.get_opts().filter(|arg| arg.is_required_set()) {
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => continue,
+        };
+        words.push(flag);
+        words.extend(placeholders(arg));
+    }
+    for arg in command
+        .get_positionals()
+        .filter(|arg| arg.is_required_set())
+    {
+        words.extend(placeholders(arg));
+    }
+
+    if command.get_subcommands().next().is_none() {
+        lines.push(words.join(" "));
+        return;
+    }
+    for subcommand in command.get_subcommands() {
+        collect_examples(subcommand, words.clone(), lines);
+    }
+}
+
+fn placeholders(arg: &Arg) -> Vec<String> {
+    match arg.get_value_names() {
+        Some(value_names) => value_names
+            .iter()
+            .map(|value_name| format!("<{}>", value_name))
+            .collect(),
+        None => vec![format!("<{}>", arg.get_id().as_str().to_uppercase())],
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    arg_env_var_names, final_env_var_name, get_arg_config, get_subcommand_config, CommandConfig,
+    Config, GlobalConfig,
+};
+use clap::Command;
+
+/// Lists every env var the config can produce as tab separated
+/// `NAME  COMMAND PATH  ARG  PRESENCE` lines. Vars of required args and args
+/// with defaults are `always` present whenever their command is active, all
+/// others are `conditional`.
+pub fn list_env_vars(config: &Config) -> anyhow::Result<String> {
+    let mut lines = Vec::new();
+    collect_env_vars(
+        &config.clap_config,
+        &config.command_config,
+        &[],
+        &config.global_config,
+        config.clap_config.get_name(),
+        &mut lines,
+    )?;
+
+    if let Some(provided_env_var) = config.global_config.provided_env_var.clone() {
+        let provided_env_var = provided_env_var.into_config();
+        let name = final_env_var_name(
+            &config.global_config,
+            "",
+            &config
+                .global_config
+                .reserved_env_var(&provided_env_var.name),
+            false,
+        )?;
+        lines.push(format!("{}\t\t\talways", name));
+    }
+    if let Some(subcommand_env_var) = &config.global_config.subcommand_env_var {
+        let global_config = &config.global_config;
+        lines.push(format!(
+            "{}\t\t\talways",
+            global_config.reserved_env_var(&subcommand_env_var.name)
+        ));
+        if let Some(depth_name) = &subcommand_env_var.depth_name {
+            lines.push(format!(
+                "{}\t\t\talways",
+                global_config.reserved_env_var(depth_name)
+            ));
+        }
+    }
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    Ok(output)
+}
+
+fn collect_env_vars<'a>(
+    command: &Command,
+    config: &'a CommandConfig,
+    ancestors: &[&'a CommandConfig],
+    global_config: &GlobalConfig,
+    path: &str,
+    lines: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    for arg in command.get_arguments() {
+        let arg_name = arg.get_id().as_str();
+        let (arg_config, inherited) =
+            get_arg_config(config, ancestors, command.get_name(), arg_name)?;
+        if inherited {
+            continue;
+        }
+        let names = arg_env_var_names(global_config, "", command, arg, arg_config)?;
+
+        let export_default = arg_config
+            .export_default
+            .unwrap_or(global_config.export_defaults && !arg_config.only_if_overridden);
+        let always = arg_config.only_for.is_none()
+            && (arg.is_required_set() || (export_default && !arg.get_default_values().is_empty()));
+        let presence = if always { "always" } else { "conditional" };
+
+        let companions = [names.index_name, names.hash_name];
+        for name in std::iter::once(names.env_var.name).chain(companions.into_iter().flatten()) {
+            lines.push(format!("{}\t{}\t{}\t{}", name, path, arg_name, presence));
+        }
+    }
+
+    for group in &config.argv_groups {
+        lines.push(format!(
+            "{}\t{}\t{}\tconditional",
+            final_env_var_name(global_config, "", &group.env_var, false)?,
+            path,
+            group.args.join(",")
+        ));
+    }
+
+    for subcommand in command.get_subcommands() {
+        let name = subcommand.get_name();
+        let Some(subconfig) = get_subcommand_config(config, name) else {
+            anyhow::bail!("Missing config for subcommand '{}'", name)
+        };
+        let mut ancestors = ancestors.to_vec();
+        ancestors.push(config);
+        collect_env_vars(
+            subcommand,
+            subconfig,
+            &ancestors,
+            global_config,
+            &format!("{} {}", path, name),
+            lines,
+        )?;
+    }
+    Ok(())
+}
@@ -1,12 +1,37 @@
-use clap::{ArgMatches, Command, Parser};
+use anyhow::Context;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, Parser, Subcommand, ValueEnum};
 use clap_serde::CommandWrap;
+use output::OutputMode;
 use serde::{Deserialize, Deserializer};
 use serde_json::{Map, Value};
+use sha2::{Digest, Sha256, Sha512};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
-use std::process::Command as ProcCommand;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command as ProcCommand, ExitStatus, Stdio};
+
+mod clap_check;
+mod complete;
+mod dry_run;
+mod example;
+mod list;
+mod nix;
+mod output;
+mod schema;
+mod selftest;
+#[cfg(test)]
+mod tests;
+mod trace;
+mod validate;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -16,200 +41,2971 @@ use std::process::Command as ProcCommand;
     about = "A simple clap based arg parser for bash scripts"
 )]
 struct Cli {
-    #[arg(long, conflicts_with = "json_file")]
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        group = "config_source",
+        group = "config_file",
+        help = "Read the config from a file, detecting the format from its extension"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(long, global = true, group = "config_source")]
     json: Option<String>,
 
-    #[arg(long, value_name = "FILE", conflicts_with = "json")]
-    json_file: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        group = "config_source",
+        group = "config_file",
+        help = "Read the config from a JSON file, - reads it from stdin"
+    )]
+    json_file: Option<PathBuf>,
+
+    #[arg(long, global = true, group = "config_source")]
+    yaml: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        group = "config_source",
+        group = "config_file",
+        help = "Read the config from a YAML file, - reads it from stdin"
+    )]
+    yaml_file: Option<PathBuf>,
+
+    #[arg(long, global = true, group = "config_source")]
+    toml: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        group = "config_source",
+        group = "config_file",
+        help = "Read the config from a TOML file, - reads it from stdin"
+    )]
+    toml_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "SELF",
+        help = "Export the path of clap-bash itself as SELF, or as NAME with --add-self-to-env=NAME. Older versions exported it as lowercase `self`"
+    )]
+    add_self_to_env: Option<String>,
+
+    #[arg(
+        long,
+        help = "Export the absolute path of the config file as CLAP_BASH_CONFIG_PATH, unset for inline configs and stdin, env_prefix applies"
+    )]
+    add_config_path_to_env: bool,
+
+    #[arg(
+        long,
+        help = "Export the shell quoted command that is run as CLAP_BASH_RESOLVED_CMD, env_prefix applies"
+    )]
+    add_resolved_cmd_to_env: bool,
+
+    #[arg(long, help = "Trace how the env layers are resolved to stderr")]
+    debug: bool,
+
+    #[arg(long, help = "Print a JSON trace of every resolution stage to stderr")]
+    trace_json: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Validate the parsed arguments against a JSON schema"
+    )]
+    input_schema: Option<PathBuf>,
+
+    #[arg(
+        long,
+        group = "output",
+        group = "watchable",
+        help = "Print the env as C setenv calls instead of running the executable"
+    )]
+    c_source: bool,
+
+    #[arg(
+        long,
+        group = "output",
+        group = "watchable",
+        help = "Print a `set --` line with the parsed values for use with eval"
+    )]
+    print_positional: bool,
+
+    #[arg(
+        long,
+        group = "output",
+        group = "watchable",
+        help = "Print the env as exports plus bash helpers for splitting multi-valued vars"
+    )]
+    print_shell_functions: bool,
+
+    #[arg(
+        long,
+        group = "output",
+        group = "watchable",
+        value_name = "FILE",
+        help = "Write the env to a dotenv file instead of running the executable"
+    )]
+    env_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        group = "output",
+        group = "watchable",
+        help = "Print the generated env as eval-safe KEY='value' lines instead of running the executable"
+    )]
+    print_env: bool,
+
+    #[arg(
+        long,
+        group = "output",
+        group = "watchable",
+        value_name = "NAME",
+        help = "Print the parsed args as a bash 4 `declare -A NAME=(...)` associative array"
+    )]
+    print_assoc_array: Option<String>,
+
+    #[arg(
+        long,
+        requires = "env_file",
+        help = "Write the env file in its canonical, diff friendly form"
+    )]
+    canonical: bool,
+
+    // The detached child runs in its own session (setsid), so it won't get
+    // SIGHUP or terminal signals meant for clap-bash. Its stdin is /dev/null
+    // while stdout and stderr stay inherited.
+    #[arg(
+        long,
+        conflicts_with = "output",
+        help = "Start the executable detached and print its PID instead of exec-ing it"
+    )]
+    detach: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "detach"],
+        group = "watchable",
+        help = "Print the command that would run instead of running it"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "detach", "dry_run"],
+        help = "Run the executable as a child and exit with its code instead of exec-ing it"
+    )]
+    no_exec: bool,
+
+    #[arg(
+        long,
+        value_name = "EXECUTABLE",
+        requires = "dry_run",
+        help = "Fail unless the dry run resolves to this executable"
+    )]
+    assert_exec: Option<PathBuf>,
+
+    #[arg(
+        long,
+        num_args = 0..,
+        allow_hyphen_values = true,
+        requires = "assert_exec",
+        help = "Also fail unless the executable gets exactly these args"
+    )]
+    assert_args: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        requires = "assert_exec",
+        help = "Also fail unless the env contains this var, can be repeated"
+    )]
+    assert_env: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "VERSION",
+        help = "Override the version the wrapped command reports for --version"
+    )]
+    wrapped_version: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SHELL",
+        help = "Print a completion script for the wrapped command instead of running it, see complete-value --help for dynamic values"
+    )]
+    generate_completion: Option<CompletionShell>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "generate_completion",
+        help = "Binary name the completion script completes, defaults to the command's name"
+    )]
+    completion_bin_name: Option<String>,
+
+    #[arg(
+        long,
+        help = "Treat the trailing arguments as a full argv including the program name"
+    )]
+    argv_includes_bin: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = BoolFormat::TrueFalse,
+        help = "How boolean flags are exported, per arg flag_true/flag_false take precedence"
+    )]
+    bool_format: BoolFormat,
+
+    #[arg(
+        long,
+        help = "Prefix each level's env vars with its depth, e.g. L0_ for the root"
+    )]
+    level_prefix: bool,
+
+    #[arg(
+        long,
+        help = "Let a later arg overwrite an env var an earlier arg of the same command set"
+    )]
+    allow_env_collisions: bool,
+
+    #[arg(
+        long,
+        help = "Check the config for problems instead of running anything"
+    )]
+    validate: bool,
+
+    #[arg(
+        long,
+        requires = "validate",
+        conflicts_with = "fail_fast",
+        help = "Report every problem in the config (default)"
+    )]
+    all: bool,
+
+    #[arg(
+        long,
+        requires = "validate",
+        help = "Stop at the first problem in the config"
+    )]
+    fail_fast: bool,
+
+    #[arg(
+        long,
+        requires = "watchable",
+        requires = "config_file",
+        help = "Re-run the output mode or dry run whenever the config file changes"
+    )]
+    watch: bool,
+
+    // clap-bash consumes exactly one `--`. Everything after it, including any
+    // further `--`, is handed to the wrapped command verbatim and parsed there
+    // as its argv (with argv[0] synthesized, see --argv-includes-bin). A
+    // wrapped command with its own `last` positional therefore needs a second
+    // `--`: `clap-bash --json-file app.json -- --flag -- last-arg`. Values of
+    // `trailing_var_arg` positionals need no separator of their own.
+    #[arg(
+        last = true,
+        allow_hyphen_values = true,
+        help = "Arguments passed to the main command, everything after the first --"
+    )]
+    trailing: Vec<OsString>,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum BoolFormat {
+    #[value(name = "true-false")]
+    TrueFalse,
+    #[value(name = "1-0")]
+    OneZero,
+    #[value(name = "yes-no")]
+    YesNo,
+}
+
+impl BoolFormat {
+    fn format(self, value: bool) -> &'static str {
+        match (self, value) {
+            (BoolFormat::TrueFalse, true) => "true",
+            (BoolFormat::TrueFalse, false) => "false",
+            (BoolFormat::OneZero, true) => "1",
+            (BoolFormat::OneZero, false) => "0",
+            (BoolFormat::YesNo, true) => "yes",
+            (BoolFormat::YesNo, false) => "no",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl CompletionShell {
+    fn shell(self) -> clap_complete::Shell {
+        match self {
+            CompletionShell::Bash => clap_complete::Shell::Bash,
+            CompletionShell::Zsh => clap_complete::Shell::Zsh,
+            CompletionShell::Fish => clap_complete::Shell::Fish,
+            CompletionShell::Powershell => clap_complete::Shell::PowerShell,
+        }
+    }
+}
+
+/// Scripts from `--generate-completion` only know the static parts of the
+/// config, dynamic values need a hook calling back into `complete-value`.
+const COMPLETE_VALUE_ABOUT: &str = "\
+Print completion candidates for an argument using its complete_cmd.
+
+Scripts from --generate-completion don't call this on their own. Hook it in
+for the args that have a complete_cmd, e.g. in bash after sourcing the
+generated script:
+
+    _app_dynamic() {
+        local cur=${COMP_WORDS[COMP_CWORD]} prev=${COMP_WORDS[COMP_CWORD-1]}
+        if [[ $prev == --branch ]]; then
+            mapfile -t COMPREPLY < <(clap-bash --json-file /path/to/app.json \\
+                complete-value --subcommand-path checkout --arg branch --prefix \"$cur\")
+            return
+        fi
+        _app \"$@\"
+    }
+    complete -F _app_dynamic app";
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    #[command(about = "Print a Nix expression that wraps the config with clap-bash")]
+    GenerateNix,
+    #[command(about = "List every env var the config can produce without running anything")]
+    ListEnvVars,
+    #[command(about = "Print a sample invocation for every command of the config")]
+    Example,
+    #[command(
+        about = "Run a built-in sample config through the whole pipeline and report each stage"
+    )]
+    Selftest,
+    #[command(
+        about = "Print completion candidates for an argument using its complete_cmd",
+        long_about = COMPLETE_VALUE_ABOUT
+    )]
+    CompleteValue {
+        #[arg(long, help = "Id of the argument to complete")]
+        arg: String,
+
+        #[arg(
+            long,
+            value_name = "PATH",
+            default_value = "",
+            help = "Space separated subcommands leading to the argument, e.g. \"remote add\""
+        )]
+        subcommand_path: String,
+
+        #[arg(
+            long,
+            default_value = "",
+            help = "Only print candidates with this prefix"
+        )]
+        prefix: String,
+    },
+}
+
+#[derive(Debug)]
+struct Config {
+    clap_config: Command,
+    command_config: CommandConfig,
+    global_config: GlobalConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlobalConfig {
+    /// Env layers from highest to lowest priority. Layers that aren't listed
+    /// are not passed to the executable at all.
+    #[serde(default = "default_env_layers")]
+    env_layers: Vec<EnvLayer>,
+
+    /// Opt-in var listing the ids of the args given on the command line for
+    /// the active command, e.g. `"CLAP_BASH_PROVIDED"`.
+    provided_env_var: Option<EnvVar>,
+
+    /// Export the clap default of args that weren't passed. Per arg
+    /// `export_default` and `only_if_overridden` take precedence.
+    #[serde(default = "default_export_defaults")]
+    export_defaults: bool,
+
+    /// Prepended to every derived env var name, e.g. `"MYTOOL_"`, and to the
+    /// vars clap-bash reserves. Explicit `env_var` names are used as they are.
+    env_prefix: Option<String>,
+
+    /// Opt-in var with the invoked subcommand chain, e.g. `"remote add"`.
+    /// `env_prefix` applies to its names as well.
+    subcommand_env_var: Option<SubcommandEnvVar>,
+
+    env_var_name_limit: Option<EnvVarNameLimit>,
+
+    /// Arg ids in the order `--print-positional` emits them. Missing args
+    /// keep their slot as an empty string. Defaults to definition order.
+    positional_order: Option<Vec<String>>,
+
+    /// Whether positionals are exported under their name, their index like
+    /// `POS_1` or both.
+    #[serde(default)]
+    positional_env_vars: PositionalEnvVars,
+
+    #[serde(default = "default_positional_index_prefix")]
+    positional_index_prefix: String,
+
+    /// Joins namespace prefixes like the `--level-prefix` ones to var names.
+    #[serde(default = "default_namespace_separator")]
+    namespace_separator: String,
+
+    /// Deepest subcommand nesting the config may have, checked before the
+    /// config is split and again while running.
+    #[serde(default = "default_max_depth")]
+    max_depth: usize,
+}
+
+impl GlobalConfig {
+    /// Name of a var clap-bash reserves, like `CLAP_BASH_CONFIG_PATH`, with
+    /// the `env_prefix` applied like for derived names.
+    fn reserved_env_var(&self, name: &str) -> String {
+        format!("{}{}", self.env_prefix.as_deref().unwrap_or(""), name)
+    }
+}
+
+fn default_export_defaults() -> bool {
+    true
+}
+
+fn default_post_exec_on_failure() -> bool {
+    true
+}
+
+fn default_max_depth() -> usize {
+    DEFAULT_MAX_DEPTH
+}
+
+fn default_positional_index_prefix() -> String {
+    "POS_".to_string()
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PositionalEnvVars {
+    #[default]
+    Name,
+    Index,
+    Both,
+}
+
+fn default_namespace_separator() -> String {
+    "_".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct SubcommandEnvVar {
+    #[serde(default = "default_subcommand_env_var_name")]
+    name: String,
+    #[serde(default = "default_subcommand_delimiter")]
+    delimiter: String,
+    /// Also export the number of subcommands under this name.
+    depth_name: Option<String>,
+}
+
+fn default_subcommand_env_var_name() -> String {
+    "CLAP_SUBCOMMAND".to_string()
+}
+
+fn default_subcommand_delimiter() -> String {
+    " ".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct EnvVarNameLimit {
+    max_length: usize,
+    #[serde(default)]
+    policy: EnvVarNameLimitPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EnvVarNameLimitPolicy {
+    #[default]
+    Error,
+    /// Keep the start of the name and replace the tail with a short hash of
+    /// the full name, so truncated names stay unique and deterministic.
+    Truncate,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum EnvLayer {
+    /// Variables generated from the parsed arguments.
+    Generated,
+    /// Variables read from the `env_file`s along the subcommand path.
+    File,
+    /// The environment clap-bash itself was started with.
+    Inherited,
+}
+
+fn default_env_layers() -> Vec<EnvLayer> {
+    vec![EnvLayer::Generated, EnvLayer::File, EnvLayer::Inherited]
+}
+
+/// The exported value of a single arg along with the env var it went to.
+struct ArgValue {
+    arg_name: String,
+    env_var: EnvVarConfig,
+    value: String,
+}
+
+/// State accumulated while recursing down the matched subcommands.
+#[derive(Default)]
+struct RunState {
+    env: BTreeMap<String, String>,
+    /// Env files accumulate down the tree, so deeper files override their
+    /// parents' values.
+    file_env: BTreeMap<String, String>,
+    arg_values: Vec<ArgValue>,
+    /// Forced `static_env` entries, re-applied after every level's args.
+    forced_env: BTreeMap<String, String>,
+    /// Number of subcommands between the root and the current command.
+    depth: usize,
+    /// Args with `forward` set, in config order from the root down.
+    forwarded_args: Vec<String>,
+    /// Raw bytes of generated vars whose values aren't valid UTF-8, which
+    /// `env` only holds lossily converted.
+    raw_env: BTreeMap<String, OsString>,
+    trace: Option<trace::Trace>,
+}
+
+struct RunContext<'a> {
+    global_config: &'a GlobalConfig,
+    debug: bool,
+    subcommand_path: Vec<String>,
+    output: Option<OutputMode>,
+    canonical: bool,
+    detach: bool,
+    dry_run: bool,
+    no_exec: bool,
+    add_resolved_cmd_to_env: bool,
+    exec_assertion: Option<dry_run::ExecAssertion>,
+    bool_format: BoolFormat,
+    level_prefix: bool,
+    allow_env_collisions: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandConfig {
+    executable: Option<PathBuf>,
+
+    #[serde(default)]
+    args: Vec<HashMap<String, ArgConfig>>,
+
+    #[serde(default)]
+    subcommands: Vec<HashMap<String, CommandConfig>>,
+
+    /// Run the executable through `bash -lc` so profile files are sourced first.
+    /// The generated and env file vars are re-exported after the profile with
+    /// the values `env_layers` resolved, so they win over variables set there.
+    #[serde(default)]
+    login_shell: bool,
+
+    /// Fixed args passed to the executable, e.g. `["--internal-mode"]`.
+    /// `${NAME}` is replaced by the value of the generated env var `NAME`,
+    /// or an empty string if there is none.
+    #[serde(default)]
+    exec_args: Vec<String>,
+
+    /// Command whose stdout is used as the executable path, e.g.
+    /// `["which-tool", "mytool"]`. Takes precedence over `executable`.
+    executable_resolver: Option<Vec<String>>,
+
+    /// User facing messages replacing the generic errors for this command.
+    missing_executable_message: Option<String>,
+    missing_subcommand_message: Option<String>,
+
+    /// Dotenv file merged into the env. Parsed args take precedence over it
+    /// by default, see `env_layers`.
+    env_file: Option<PathBuf>,
+
+    /// Run unknown external subcommands (see clap's
+    /// `allow_external_subcommands`) as the executable, passing their args.
+    #[serde(default)]
+    external_passthrough: bool,
+
+    clap_settings: Option<ClapSettings>,
+
+    /// Feeds the executable's stdin from a file or the exported value of an
+    /// arg, e.g. `{"file": "input.txt"}` or `{"arg": "input"}`. Like
+    /// `post_exec` this runs the executable as a child instead of exec-ing
+    /// it and can't be combined with `--detach`.
+    stdin: Option<StdinSource>,
+
+    /// Command run with the same env after the executable exits, e.g. for
+    /// cleanup. The executable then runs as a child instead of being
+    /// exec-ed. clap-bash exits with the executable's code if it failed and
+    /// with the post_exec code otherwise.
+    post_exec: Option<Vec<String>>,
+
+    /// Also run `post_exec` when the executable failed.
+    #[serde(default = "default_post_exec_on_failure")]
+    post_exec_on_failure: bool,
+
+    #[serde(default)]
+    argv_groups: Vec<ArgvGroup>,
+
+    /// Fixed vars added to the generated env. Named `static_env` since `env`
+    /// already is a clap key. Parsed args win over these unless an entry
+    /// sets `force`, which makes it win over the args of this command and
+    /// all its subcommands. Either way they are part of the generated layer,
+    /// so `env_layers` still decides how they rank against env files and the
+    /// inherited env.
+    #[serde(default)]
+    static_env: BTreeMap<String, StaticEnvValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StdinSource {
+    File(PathBuf),
+    Arg(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StaticEnvValue {
+    Simple(String),
+    Full {
+        value: String,
+        #[serde(default)]
+        force: bool,
+    },
+}
+
+impl StaticEnvValue {
+    fn value(&self) -> &str {
+        match self {
+            StaticEnvValue::Simple(value) => value,
+            StaticEnvValue::Full { value, .. } => value,
+        }
+    }
+
+    fn force(&self) -> bool {
+        matches!(self, StaticEnvValue::Full { force: true, .. })
+    }
+}
+
+/// Exports the command line tokens of `args` in argv order as one shell
+/// quoted string, e.g. to forward a subset of flags to another tool. An arg
+/// listed in several groups shows up in each of them.
+#[derive(Debug, Deserialize)]
+struct ArgvGroup {
+    env_var: String,
+    args: Vec<String>,
+}
+
+impl ArgvGroup {
+    /// Members that aren't args of `command`, they could never match.
+    fn unknown_members<'a>(&'a self, command: &Command) -> Vec<&'a str> {
+        self.args
+            .iter()
+            .map(String::as_str)
+            .filter(|member| {
+                !command
+                    .get_arguments()
+                    .any(|arg| arg.get_id().as_str() == *member)
+            })
+            .collect()
+    }
+}
+
+/// `Command` settings applied after `clap_serde` built the command. Every
+/// field calls the builder method of the same name with its value.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClapSettings {
+    infer_subcommands: Option<bool>,
+    infer_long_args: Option<bool>,
+    args_conflicts_with_subcommands: Option<bool>,
+    subcommand_precedence_over_arg: Option<bool>,
+    subcommand_negates_reqs: Option<bool>,
+    allow_external_subcommands: Option<bool>,
+    allow_missing_positional: Option<bool>,
+    propagate_version: Option<bool>,
+    disable_help_subcommand: Option<bool>,
+}
+
+impl ClapSettings {
+    fn apply(&self, mut command: Command) -> Command {
+        if let Some(value) = self.infer_subcommands {
+            command = command.infer_subcommands(value);
+        }
+        if let Some(value) = self.infer_long_args {
+            command = command.infer_long_args(value);
+        }
+        if let Some(value) = self.args_conflicts_with_subcommands {
+            command = command.args_conflicts_with_subcommands(value);
+        }
+        if let Some(value) = self.subcommand_precedence_over_arg {
+            command = command.subcommand_precedence_over_arg(value);
+        }
+        if let Some(value) = self.subcommand_negates_reqs {
+            command = command.subcommand_negates_reqs(value);
+        }
+        if let Some(value) = self.allow_external_subcommands {
+            command = command.allow_external_subcommands(value);
+        }
+        if let Some(value) = self.allow_missing_positional {
+            command = command.allow_missing_positional(value);
+        }
+        if let Some(value) = self.propagate_version {
+            command = command.propagate_version(value);
+        }
+        if let Some(value) = self.disable_help_subcommand {
+            command = command.disable_help_subcommand(value);
+        }
+        command
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct EnvVarConfig {
+    name: String,
+    #[serde(default = "default_value_delimiter")]
+    value_delimiter: String,
+    #[serde(default = "default_occurrence_delimiter")]
+    occurrence_delimiter: String,
+}
+
+fn default_value_delimiter() -> String {
+    ";".to_string()
+}
+fn default_occurrence_delimiter() -> String {
+    ",".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum EnvVar {
+    Simple(String),
+    Full(EnvVarConfig),
+}
+
+impl EnvVar {
+    fn into_config(self) -> EnvVarConfig {
+        match self {
+            EnvVar::Simple(name) => EnvVarConfig {
+                name,
+                value_delimiter: default_value_delimiter(),
+                occurrence_delimiter: default_occurrence_delimiter(),
+            },
+            EnvVar::Full(cfg) => cfg,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ArgConfig {
+    /// Explicit env var name, used literally, e.g. a lowercase `http_proxy`.
+    /// Without it the name is derived from the arg id as an uppercase
+    /// identifier and subject to `env_var_name_limit`.
+    env_var: Option<EnvVar>,
+
+    /// Format deriving the env var name instead of the default uppercase
+    /// id, e.g. `"{kind}_{ID}"`. See `format_env_var_name` for placeholders.
+    env_var_format: Option<String>,
+
+    /// Also export a hash of the joined value as `<ENV_VAR>_<ALGORITHM>`.
+    hash: Option<HashAlgorithm>,
+
+    /// Only export this arg when the active subcommand path starts with one of
+    /// these space separated paths, e.g. `["build", "remote add"]`.
+    only_for: Option<Vec<String>>,
+
+    /// Treat each value as a file path and export the file's contents
+    /// instead. Trailing newlines are stripped, as secrets usually must not
+    /// end in one, unless `keep_trailing_newline` is set.
+    #[serde(default)]
+    read_from_file: bool,
+    #[serde(default)]
+    keep_trailing_newline: bool,
+
+    /// Replace a leading `~` or `~/` with `$HOME`, like the shell does for
+    /// unquoted words. `~user` forms are left alone.
+    #[serde(default)]
+    expand_tilde: bool,
+
+    /// Removed from each value, but only if the value actually has it.
+    strip_prefix: Option<String>,
+    strip_suffix: Option<String>,
+
+    /// Treat each value as a `:` or `;` separated path list and rejoin it
+    /// with the host's separator.
+    #[serde(default)]
+    path_list: bool,
+
+    /// Command run once per value with the value on stdin and the arg id in
+    /// `CLAP_BASH_ARG`. Its stdout, minus trailing newlines, replaces the
+    /// value. This spawns a process for every single value, so prefer the
+    /// built-in options where they suffice.
+    transform_cmd: Option<Vec<String>>,
+
+    /// Built-in rewrite of every single value, `"uppercase"`, `"lowercase"`,
+    /// `"trim"` or a `{"map": {...}}` table.
+    transform: Option<Transform>,
+
+    value_type: Option<ValueType>,
+
+    /// Inclusive bounds every value has to be within, checked after
+    /// `value_type`. `range_message` replaces the generic error, with
+    /// `{value}` replaced by the offending value.
+    min: Option<f64>,
+    max: Option<f64>,
+    range_message: Option<String>,
+
+    /// Exported values of a `SetTrue`/`SetFalse` flag, overriding
+    /// `--bool-format` for this arg.
+    flag_true: Option<String>,
+    flag_false: Option<String>,
+
+    /// Reduces all values of all occurrences to a single value instead of
+    /// joining them.
+    reduce: Option<Reducer>,
+
+    /// Value used when the arg isn't present, with `${NAME}` replaced by the
+    /// env vars computed for the other args, e.g. `"${NAME}.out"`.
+    default_template: Option<String>,
+
+    /// Command printing completion candidates for this arg, one per line,
+    /// used by `clap-bash complete-value`.
+    complete_cmd: Option<Vec<String>>,
+
+    /// Encoding applied to each value after all other transforms.
+    encode: Option<Encoding>,
+
+    /// Skip the env var when the value only comes from the clap default. An
+    /// explicitly passed value equal to the default is still exported.
+    #[serde(default)]
+    only_if_overridden: bool,
+
+    /// Overrides the global `export_defaults` for this arg.
+    export_default: Option<bool>,
+
+    /// Also pass the value on the executable's command line, after its
+    /// `exec_args`. `true` reuses the arg's own flag, a string replaces it.
+    forward: Option<Forward>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Forward {
+    Enabled(bool),
+    Flag(String),
+}
+
+impl Forward {
+    /// The args forwarded for one matched arg. Values are repeated with the
+    /// flag per occurrence, `SetTrue`/`SetFalse` flags are only forwarded
+    /// when passed on the command line and `Count` flags as often as they
+    /// were counted. Positionals forwarded with `true` have no flag, only
+    /// their values. Counts are read from the raw matched value, transforms
+    /// only apply to forwarded values.
+    fn args(
+        &self,
+        arg: &Arg,
+        args: &ArgMatches,
+        occurrences: &[Vec<String>],
+    ) -> anyhow::Result<Vec<String>> {
+        let flag = match self {
+            Forward::Enabled(false) => return Ok(Vec::new()),
+            Forward::Enabled(true) => match (arg.get_long(), arg.get_short()) {
+                (Some(long), _) => Some(format!("--{}", long)),
+                (None, Some(short)) => Some(format!("-{}", short)),
+                (None, None) => None,
+            },
+            Forward::Flag(flag) => Some(flag.clone()),
+        };
+        let arg_name = arg.get_id().as_str();
+
+        let forwarded = match (arg.get_action(), flag) {
+            (ArgAction::SetTrue | ArgAction::SetFalse, Some(flag)) => {
+                if args.value_source(arg_name) == Some(ValueSource::CommandLine) {
+                    vec![flag]
+                } else {
+                    Vec::new()
+                }
+            }
+            (ArgAction::Count, Some(flag)) => {
+                let value = raw_flag_value(args, arg_name);
+                let count: usize = value.parse().map_err(|_| {
+                    anyhow::anyhow!("Can't forward count '{}' of {}", value, arg.get_id())
+                })?;
+                vec![flag; count]
+            }
+            (_, Some(flag)) => occurrences
+                .iter()
+                .flat_map(|occurrence| std::iter::once(flag.clone()).chain(occurrence.clone()))
+                .collect(),
+            (_, None) => occurrences.concat(),
+        };
+        Ok(forwarded)
+    }
+}
+
+/// Normalizes values to a canonical form: booleans become `true`/`false`,
+/// integers lose leading zeros and `+` signs and floats use Rust's shortest
+/// round-trip formatting.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ValueType {
+    Bool,
+    Int,
+    Float,
+}
+
+impl ValueType {
+    fn normalize(self, arg_name: &str, value: &str) -> anyhow::Result<String> {
+        let normalized = match self {
+            ValueType::Bool => match value.to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" | "on" => Some("true".to_string()),
+                "false" | "0" | "no" | "off" => Some("false".to_string()),
+                _ => None,
+            },
+            ValueType::Int => value.parse::<i128>().ok().map(|int| int.to_string()),
+            ValueType::Float => value.parse::<f64>().ok().map(|float| float.to_string()),
+        };
+        normalized.with_context(|| {
+            format!(
+                "Value '{}' of arg '{}' is not a valid {:?}",
+                value, arg_name, self
+            )
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Reducer {
+    Sum,
+    Max,
+    Min,
+    First,
+    Last,
+    Count,
+}
+
+impl Reducer {
+    fn reduce(self, arg_name: &str, values: &[String]) -> anyhow::Result<String> {
+        match self {
+            Reducer::First => Ok(values.first().cloned().unwrap_or_default()),
+            Reducer::Last => Ok(values.last().cloned().unwrap_or_default()),
+            Reducer::Count => Ok(values.len().to_string()),
+            Reducer::Sum | Reducer::Max | Reducer::Min => self.reduce_numbers(arg_name, values),
+        }
+    }
+
+    /// Integers stay integers, as soon as one value is a float the result
+    /// is one too. Integer sums that overflow are an error.
+    fn reduce_numbers(self, arg_name: &str, values: &[String]) -> anyhow::Result<String> {
+        if let Ok(ints) = values
+            .iter()
+            .map(|value| value.parse::<i128>())
+            .collect::<Result<Vec<_>, _>>()
+        {
+            let result = match self {
+                Reducer::Sum => Some(
+                    ints.iter()
+                        .try_fold(0i128, |sum, int| sum.checked_add(*int))
+                        .with_context(|| {
+                            format!("The sum of the values of arg '{}' overflows", arg_name)
+                        })?,
+                ),
+                Reducer::Max => ints.iter().copied().max(),
+                _ => ints.iter().copied().min(),
+            };
+            return Ok(result.map(|int| int.to_string()).unwrap_or_default());
+        }
+
+        let floats = values
+            .iter()
+            .map(|value| {
+                value.parse::<f64>().with_context(|| {
+                    format!(
+                        "Value '{}' of arg '{}' is not a number, which the {:?} reducer needs",
+                        value, arg_name, self
+                    )
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let result = match self {
+            Reducer::Sum => floats.iter().sum(),
+            Reducer::Max => floats.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            _ => floats.iter().copied().fold(f64::INFINITY, f64::min),
+        };
+        Ok(result.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CaseTransform {
+    Uppercase,
+    Lowercase,
+    Trim,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Transform {
+    Simple(CaseTransform),
+    /// Rewrites values found in `map`. Values missing from it pass through
+    /// unchanged unless `strict` is set, which makes them an error, so a map
+    /// can cover just the aliases and leave canonical values alone.
+    Map {
+        map: BTreeMap<String, String>,
+        #[serde(default)]
+        strict: bool,
+    },
+}
+
+impl Transform {
+    fn apply(&self, arg_name: &str, value: String) -> anyhow::Result<String> {
+        match self {
+            Transform::Simple(CaseTransform::Uppercase) => Ok(value.to_uppercase()),
+            Transform::Simple(CaseTransform::Lowercase) => Ok(value.to_lowercase()),
+            Transform::Simple(CaseTransform::Trim) => Ok(value.trim().to_string()),
+            Transform::Map { map, strict } => match map.get(&value) {
+                Some(mapped) => Ok(mapped.clone()),
+                None if *strict => anyhow::bail!(
+                    "Value '{}' of arg '{}' is not in its transform map, expected one of: {}",
+                    value,
+                    arg_name,
+                    map.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+                None => Ok(value),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    /// Percent-encodes everything except the RFC 3986 unreserved characters
+    /// `A-Z a-z 0-9 - . _ ~`, so all reserved characters are encoded.
+    Url,
+    Base64,
+}
+
+impl Encoding {
+    fn encode(self, value: &str) -> String {
+        match self {
+            Encoding::Url => url_encode(value),
+            Encoding::Base64 => BASE64_STANDARD.encode(value),
+        }
+    }
+}
+
+fn expand_tilde(value: &str) -> Cow<'_, str> {
+    let Some(rest) = value.strip_prefix('~') else {
+        return Cow::Borrowed(value);
+    };
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return Cow::Borrowed(value);
+    }
+    match std::env::var("HOME") {
+        Ok(home) => Cow::Owned(format!("{}{}", home, rest)),
+        Err(_) => Cow::Borrowed(value),
+    }
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl ArgConfig {
+    fn transform_value(&self, arg_name: &str, value: &OsStr) -> anyhow::Result<String> {
+        let value = value.to_string_lossy();
+        let value = if self.expand_tilde {
+            expand_tilde(&value)
+        } else {
+            value
+        };
+        let value = if self.read_from_file {
+            Cow::Owned(self.read_value_file(arg_name, Path::new(value.as_ref()))?)
+        } else {
+            value
+        };
+        let value = self.strip_value(&value);
+        let value = if self.path_list {
+            normalize_path_list(value)
+        } else {
+            value.to_string()
+        };
+        let value = match &self.transform_cmd {
+            Some(transform_cmd) => run_transform_cmd(transform_cmd, arg_name, &value)?,
+            None => value,
+        };
+        let value = match &self.transform {
+            Some(transform) => transform.apply(arg_name, value)?,
+            None => value,
+        };
+        let value = match self.value_type {
+            Some(value_type) => value_type.normalize(arg_name, &value)?,
+            None => value,
+        };
+        self.check_range(arg_name, &value)?;
+        Ok(match self.encode {
+            Some(encoding) => encoding.encode(&value),
+            None => value,
+        })
+    }
+
+    /// Names of the configured transform steps in the order
+    /// `transform_value` applies them.
+    fn transform_names(&self) -> Vec<&'static str> {
+        let steps = [
+            ("expand_tilde", self.expand_tilde),
+            ("read_from_file", self.read_from_file),
+            ("strip_prefix", self.strip_prefix.is_some()),
+            ("strip_suffix", self.strip_suffix.is_some()),
+            ("path_list", self.path_list),
+            ("transform_cmd", self.transform_cmd.is_some()),
+            ("transform", self.transform.is_some()),
+            ("value_type", self.value_type.is_some()),
+            ("range", self.min.is_some() || self.max.is_some()),
+            ("encode", self.encode.is_some()),
+        ];
+        steps
+            .into_iter()
+            .filter(|(_, configured)| *configured)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    fn check_range(&self, arg_name: &str, value: &str) -> anyhow::Result<()> {
+        if self.min.is_none() && self.max.is_none() {
+            return Ok(());
+        }
+        let number: f64 = value.trim().parse().with_context(|| {
+            format!(
+                "Value '{}' of arg '{}' is not a number, which min/max need",
+                value, arg_name
+            )
+        })?;
+
+        let in_range =
+            self.min.is_none_or(|min| number >= min) && self.max.is_none_or(|max| number <= max);
+        if in_range {
+            return Ok(());
+        }
+        match &self.range_message {
+            Some(message) => anyhow::bail!("{}", message.replace("{value}", value)),
+            None => anyhow::bail!(
+                "Value {} of arg '{}' is out of range {}..={}",
+                value,
+                arg_name,
+                self.min.map_or(String::new(), |min| min.to_string()),
+                self.max.map_or(String::new(), |max| max.to_string())
+            ),
+        }
+    }
+
+    fn format_flag(&self, bool_format: BoolFormat, value: bool) -> String {
+        let configured = if value {
+            &self.flag_true
+        } else {
+            &self.flag_false
+        };
+        match configured {
+            Some(configured) => configured.clone(),
+            None => bool_format.format(value).to_string(),
+        }
+    }
+
+    fn read_value_file(&self, arg_name: &str, path: &Path) -> anyhow::Result<String> {
+        let contents = fs::read_to_string(path).with_context(|| {
+            format!(
+                "Failed to read file '{}' of arg '{}'",
+                path.display(),
+                arg_name
+            )
+        })?;
+        if self.keep_trailing_newline {
+            Ok(contents)
+        } else {
+            Ok(contents.trim_end_matches(['\n', '\r']).to_string())
+        }
+    }
+
+    fn strip_value<'v>(&self, value: &'v str) -> &'v str {
+        let value = match &self.strip_prefix {
+            Some(prefix) => value.strip_prefix(prefix.as_str()).unwrap_or(value),
+            None => value,
+        };
+        match &self.strip_suffix {
+            Some(suffix) => value.strip_suffix(suffix.as_str()).unwrap_or(value),
+            None => value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn suffix(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    fn hex_digest(self, value: &str) -> String {
+        match self {
+            HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(value.as_bytes())),
+            HashAlgorithm::Sha512 => format!("{:x}", Sha512::digest(value.as_bytes())),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // The selftest brings its own config, so none has to be given.
+    if let Some(CliCommand::Selftest) = cli.command {
+        return selftest::selftest();
+    }
+
+    if cli.watch {
+        return watch::watch(&cli);
+    }
+
+    let json_data = read_config_data(&cli)?;
+    let code = process_config(&cli, &json_data)?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Runs the whole pipeline for one config and returns the exit code of the
+/// executable when it was waited for, 0 otherwise.
+fn process_config(cli: &Cli, json_data: &str) -> anyhow::Result<i32> {
+    if cli.validate {
+        let (config, problems) = Config::from_json(serde_json::from_str(json_data)?)?;
+        let fail_fast = cli.fail_fast && !cli.all;
+        let report = validate::validate(&config, problems, fail_fast)?;
+        print!("{}", report);
+        return Ok(0);
+    }
+
+    let config: Config = serde_json::from_str(json_data)?;
+
+    check_delimiters(&config)?;
+
+    match &cli.command {
+        Some(CliCommand::GenerateNix) => {
+            print!(
+                "{}",
+                nix::generate_nix(config.clap_config.get_name(), json_data)?
+            );
+            return Ok(0);
+        }
+        Some(CliCommand::ListEnvVars) => {
+            print!("{}", list::list_env_vars(&config)?);
+            return Ok(0);
+        }
+        Some(CliCommand::Example) => {
+            print!("{}", example::example_invocations(&config.clap_config));
+            return Ok(0);
+        }
+        Some(CliCommand::Selftest) => {
+            selftest::selftest()?;
+            return Ok(0);
+        }
+        Some(CliCommand::CompleteValue {
+            arg,
+            subcommand_path,
+            prefix,
+        }) => {
+            print!(
+                "{}",
+                complete::complete_value(&config.command_config, subcommand_path, arg, prefix)?
+            );
+            return Ok(0);
+        }
+        None => {}
+    }
+
+    let mut app = config.clap_config;
+    if let Some(version) = &cli.wrapped_version {
+        app = app.version(version.clone());
+    }
+
+    if let Some(shell) = cli.generate_completion {
+        let bin_name = cli
+            .completion_bin_name
+            .clone()
+            .unwrap_or_else(|| app.get_name().to_string());
+        clap_complete::generate(shell.shell(), &mut app, bin_name, &mut std::io::stdout());
+        return Ok(0);
+    }
+    let command_config = config.command_config;
+
+    let mut args = cli.trailing.clone();
+    let app_name = app.get_name();
+    if !cli.argv_includes_bin {
+        if args.first().is_some_and(|arg| arg == app_name) {
+            eprintln!(
+                "warning: the first argument '{}' is the program name, pass --argv-includes-bin if it is meant as argv[0]",
+                app_name
+            );
+        }
+        args.insert(0, OsString::from(app_name));
+    } else if args.is_empty() {
+        anyhow::bail!("--argv-includes-bin requires at least the program name after --");
+    }
+
+    let matches = match app.clone().try_get_matches_from(args) {
+        Ok(matches) => matches,
+        Err(error) if !cli.watch => error.exit(),
+        Err(error) => {
+            error.print()?;
+            return Ok(0);
+        }
+    };
+
+    if let Some(schema_file) = &cli.input_schema {
+        let input = Value::Object(matches_to_json(&app, &matches));
+        schema::validate_input(schema_file, &input)?;
+    }
+
+    let mut state = RunState::default();
+
+    if cli.add_config_path_to_env {
+        if let Some((_, config_file)) = config_file(cli).filter(|(_, file)| !is_stdin(file)) {
+            let config_path = fs::canonicalize(config_file).with_context(|| {
+                format!("Failed to resolve config path '{}'", config_file.display())
+            })?;
+            state.env.insert(
+                config
+                    .global_config
+                    .reserved_env_var("CLAP_BASH_CONFIG_PATH"),
+                config_path.to_string_lossy().into_owned(),
+            );
+        }
+    }
+
+    if cli.trace_json {
+        let mut trace = trace::Trace::default();
+        match (config_file(cli), inline_config(cli)) {
+            (Some((format, path)), _) => trace.input(
+                &format!("{}_file", format.name()),
+                Some(&path.to_string_lossy()),
+            ),
+            (None, Some((format, _))) => trace.input(format.name(), None),
+            (None, None) => {}
+        }
+        trace.split(json_data)?;
+        trace.subcommand_path(&active_subcommand_path(&matches));
+        state.trace = Some(trace);
+    }
+
+    if let Some(name) = &cli.add_self_to_env {
+        if !is_posix_identifier(name) {
+            anyhow::bail!(
+                "--add-self-to-env name '{}' is not a valid identifier",
+                name
+            );
+        }
+        let name = config.global_config.reserved_env_var(name);
+        match std::env::current_exe() {
+            Ok(exe) => {
+                if exe.to_str().is_none() {
+                    eprintln!(
+                        "warning: the path of clap-bash is not valid UTF-8, {} holds a lossy copy",
+                        name
+                    );
+                }
+                state.env.insert(name, exe.to_string_lossy().into_owned());
+            }
+            Err(error) => eprintln!("warning: can't determine the path of clap-bash: {}", error),
+        }
+    }
+
+    let ctx = RunContext {
+        global_config: &config.global_config,
+        debug: cli.debug,
+        subcommand_path: active_subcommand_path(&matches),
+        output: output_mode(cli),
+        canonical: cli.canonical,
+        detach: cli.detach,
+        dry_run: cli.dry_run,
+        no_exec: cli.no_exec,
+        add_resolved_cmd_to_env: cli.add_resolved_cmd_to_env,
+        exec_assertion: cli
+            .assert_exec
+            .clone()
+            .map(|executable| dry_run::ExecAssertion {
+                executable,
+                args: cli.assert_args.clone(),
+                env: cli.assert_env.clone(),
+            }),
+        bool_format: cli.bool_format,
+        level_prefix: cli.level_prefix,
+        allow_env_collisions: cli.allow_env_collisions,
+    };
+
+    run(&ctx, &app, &matches, &command_config, &[], state)
+}
+
+fn output_mode(cli: &Cli) -> Option<OutputMode> {
+    if cli.c_source {
+        Some(OutputMode::CSource)
+    } else if cli.print_positional {
+        Some(OutputMode::Positional)
+    } else if cli.print_shell_functions {
+        Some(OutputMode::ShellFunctions)
+    } else if let Some(path) = &cli.env_file {
+        Some(OutputMode::EnvFile(path.clone()))
+    } else if cli.print_env {
+        Some(OutputMode::PrintEnv)
+    } else if let Some(name) = &cli.print_assoc_array {
+        Some(OutputMode::AssocArray(name.clone()))
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ConfigFormat {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Json5 => "json5",
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Toml => "toml",
+        }
+    }
+
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(ConfigFormat::Json),
+            "json5" => Some(ConfigFormat::Json5),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "toml" => Some(ConfigFormat::Toml),
+            _ => None,
+        }
+    }
+
+    /// Converts the config to JSON, which the rest of the pipeline works on
+    /// regardless of the format the config was written in.
+    fn to_json(self, data: &str) -> anyhow::Result<String> {
+        let value: Value = match self {
+            ConfigFormat::Json => return Ok(data.to_string()),
+            ConfigFormat::Json5 => json5::from_str(data).context("Failed to parse JSON5 config")?,
+            ConfigFormat::Yaml => {
+                serde_yaml::from_str(data).context("Failed to parse YAML config")?
+            }
+            ConfigFormat::Toml => toml::from_str(data).context("Failed to parse TOML config")?,
+        };
+        Ok(serde_json::to_string(&value)?)
+    }
+}
+
+fn inline_config(cli: &Cli) -> Option<(ConfigFormat, &str)> {
+    [
+        (ConfigFormat::Json, &cli.json),
+        (ConfigFormat::Yaml, &cli.yaml),
+        (ConfigFormat::Toml, &cli.toml),
+    ]
+    .into_iter()
+    .find_map(|(format, data)| Some((format, data.as_deref()?)))
+}
+
+/// The config file and its format. `--config` detects the format from the
+/// extension and falls back to JSON for unknown ones.
+fn config_file(cli: &Cli) -> Option<(ConfigFormat, &Path)> {
+    if let Some(file) = &cli.config {
+        let format = ConfigFormat::from_extension(file).unwrap_or(ConfigFormat::Json);
+        return Some((format, file));
+    }
+    [
+        (ConfigFormat::Json, &cli.json_file),
+        (ConfigFormat::Yaml, &cli.yaml_file),
+        (ConfigFormat::Toml, &cli.toml_file),
+    ]
+    .into_iter()
+    .find_map(|(format, file)| Some((format, file.as_deref()?)))
+}
+
+const MISSING_CONFIG: &str =
+    "You must provide the config with --config, --json, --yaml, --toml or one of their --*-file variants";
+
+/// `-` as config file reads the config from stdin.
+fn is_stdin(file: &Path) -> bool {
+    file == Path::new("-")
+}
+
+fn read_config_data(cli: &Cli) -> anyhow::Result<String> {
+    if let Some((format, data)) = inline_config(cli) {
+        format.to_json(data)
+    } else if let Some((format, file)) = config_file(cli) {
+        let data = if is_stdin(file) {
+            let data = std::io::read_to_string(std::io::stdin())
+                .context("Failed to read config from stdin")?;
+            if data.trim().is_empty() {
+                anyhow::bail!("{} (stdin was empty)", MISSING_CONFIG);
+            }
+            data
+        } else {
+            fs::read_to_string(file)
+                .with_context(|| format!("Failed to read config file '{}'", file.display()))?
+        };
+        if cli.config.is_some() && ConfigFormat::from_extension(file).is_none() {
+            serde_json::from_str::<Value>(&data).with_context(|| {
+                format!(
+                    "Unknown config file extension of '{}', use .json, .json5, .yaml, .yml or .toml or a --*-file flag. Parsing it as JSON failed",
+                    file.display()
+                )
+            })?;
+        }
+        format.to_json(&data)
+    } else {
+        anyhow::bail!("{}", MISSING_CONFIG)
+    }
+}
+
+fn matches_to_json(command: &Command, matches: &ArgMatches) -> Map<String, Value> {
+    let mut args_json = Map::new();
+    for arg in command.get_arguments() {
+        let arg_name = arg.get_id().as_str();
+        let Some(raw_arg_values) = matches.get_raw_occurrences(arg_name) else {
+            continue;
+        };
+
+        let occurrences = raw_arg_values
+            .map(|occurrence| {
+                occurrence
+                    .map(|value| Value::String(value.to_string_lossy().into_owned()))
+                    .collect::<Vec<_>>()
+            })
+            .map(Value::Array)
+            .collect();
+        args_json.insert(arg_name.to_string(), Value::Array(occurrences));
+    }
+
+    let mut json = Map::new();
+    json.insert("args".to_string(), Value::Object(args_json));
+
+    if let Some((name, submatches)) = matches.subcommand() {
+        // External subcommands have no definition to recurse into, only the
+        // raw args clap collected for them.
+        let mut subcommand_json = match get_subcommand(command, name) {
+            Some(subcommand) => matches_to_json(subcommand, submatches),
+            None => {
+                let raw_args = external_args(submatches)
+                    .into_iter()
+                    .map(|arg| Value::String(arg.to_string_lossy().into_owned()))
+                    .collect();
+                let mut json = Map::new();
+                json.insert("external_args".to_string(), Value::Array(raw_args));
+                json
+            }
+        };
+        subcommand_json.insert("name".to_string(), Value::String(name.to_string()));
+        json.insert("subcommand".to_string(), Value::Object(subcommand_json));
+    }
+    json
+}
+
+fn active_subcommand_path(matches: &ArgMatches) -> Vec<String> {
+    let mut path = Vec::new();
+    let mut current = matches;
+    while let Some((name, submatches)) = current.subcommand() {
+        path.push(name.to_string());
+        current = submatches;
+    }
+    path
+}
+
+fn run(
+    ctx: &RunContext,
+    command: &Command,
+    args: &ArgMatches,
+    config: &CommandConfig,
+    ancestors: &[&CommandConfig],
+    mut state: RunState,
+) -> anyhow::Result<i32> {
+    if let Some(env_file) = &config.env_file {
+        let file_env = read_env_file(env_file)?;
+        state.file_env.extend(file_env);
+    }
+
+    for (key, value) in &config.static_env {
+        let target = if value.force() {
+            &mut state.forced_env
+        } else {
+            &mut state.env
+        };
+        target.insert(key.clone(), value.value().to_string());
+    }
+
+    let env_vars = create_env_vars(ctx, command, args, config, ancestors, &mut state)?;
+    state.env.extend(env_vars);
+    state.env.extend(state.forced_env.clone());
+
+    if let Some((name, subargs)) = args.subcommand() {
+        if let Some(subconfig) = get_subcommand_config(config, name) {
+            let Some(subcommand) = get_subcommand(command, name) else {
+                anyhow::bail!("Subcommand '{}' has a config but no clap definition", name)
+            };
+            state.depth += 1;
+            if state.depth > ctx.global_config.max_depth {
+                anyhow::bail!(
+                    "Subcommands are nested deeper than max_depth ({})",
+                    ctx.global_config.max_depth
+                );
+            }
+            let mut ancestors = ancestors.to_vec();
+            ancestors.push(config);
+            return run(ctx, subcommand, subargs, subconfig, &ancestors, state);
+        }
+        if !config.external_passthrough {
+            match &config.missing_subcommand_message {
+                Some(message) => anyhow::bail!("{}", message),
+                None => anyhow::bail!("Missing config for subcommand '{}'", name),
+            }
+        }
+    }
+
+    if let Some(subcommand_env_var) = &ctx.global_config.subcommand_env_var {
+        state.env.insert(
+            ctx.global_config.reserved_env_var(&subcommand_env_var.name),
+            ctx.subcommand_path.join(&subcommand_env_var.delimiter),
+        );
+        if let Some(depth_name) = &subcommand_env_var.depth_name {
+            state.env.insert(
+                ctx.global_config.reserved_env_var(depth_name),
+                ctx.subcommand_path.len().to_string(),
+            );
+        }
+    }
+
+    if let Some(mut trace) = state.trace.take() {
+        trace.env(&state.env, &state.file_env);
+        trace.emit()?;
+    }
+
+    if let Some(output) = &ctx.output {
+        match output {
+            OutputMode::CSource => print!("{}", output::c_source(&state.env)),
+            OutputMode::Positional => print!(
+                "{}",
+                output::positional(&ordered_arg_values(ctx, &state.arg_values))
+            ),
+            OutputMode::ShellFunctions => {
+                print!("{}", output::shell_functions(&state.env, &state.arg_values))
+            }
+            OutputMode::EnvFile(path) => {
+                fs::write(path, output::dotenv(&state.env, ctx.canonical))?;
+            }
+            OutputMode::PrintEnv => {
+                let executable = match args.subcommand() {
+                    Some((name, _)) => PathBuf::from(name),
+                    None => resolve_executable(config, &state.env)?,
+                };
+                print!(
+                    "{}",
+                    output::print_env(&state.env, &executable, &ctx.subcommand_path)
+                );
+            }
+            OutputMode::AssocArray(name) => {
+                if !is_posix_identifier(name) {
+                    anyhow::bail!("'{}' is not a valid bash array name", name);
+                }
+                print!("{}", output::assoc_array(name, &state.arg_values));
+            }
+        }
+        return Ok(0);
+    }
+
+    // Only external subcommands are left unmatched here, they run as the
+    // executable with their collected args.
+    let (executable, exec_args) = match args.subcommand() {
+        Some((name, subargs)) => (PathBuf::from(name), external_args(subargs)),
+        None => (
+            resolve_executable(config, &state.env)?,
+            config
+                .exec_args
+                .iter()
+                .map(|arg| expand_template(arg, |var| state.env.get(var).cloned()))
+                .chain(state.forwarded_args.iter().cloned())
+                .map(OsString::from)
+                .collect(),
+        ),
+    };
+
+    if ctx.add_resolved_cmd_to_env {
+        state.env.insert(
+            ctx.global_config.reserved_env_var("CLAP_BASH_RESOLVED_CMD"),
+            quote_command(&executable, &exec_args),
+        );
+    }
+
+    let resolved_env = resolve_env_layers(ctx, &state);
+    if cfg!(windows) {
+        for (first, second) in case_insensitive_collisions(&resolved_env) {
+            eprintln!(
+                "warning: env vars '{}' and '{}' collide on Windows, which ignores case in env var names",
+                first, second
+            );
+        }
+    }
+    if ctx.dry_run {
+        print!(
+            "{}",
+            dry_run::dry_run(
+                &executable,
+                &exec_args,
+                &resolved_env,
+                ctx.exec_assertion.as_ref()
+            )?
+        );
+        return Ok(0);
+    }
+
+    let mut process = if config.login_shell {
+        // Only the vars clap-bash sets are re-exported after the profile, with
+        // the value the env layers resolved for them, inherited ones are left
+        // to the profile.
+        let login_env: BTreeMap<String, String> = resolved_env
+            .iter()
+            .filter(|(key, _)| state.env.contains_key(*key) || state.file_env.contains_key(*key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        login_shell_command(&executable, &login_env)
+    } else {
+        ProcCommand::new(&executable)
+    };
+    let process_env = restore_raw_env(&resolved_env, &state);
+    process.args(exec_args).env_clear().envs(&process_env);
+
+    let stdin_value = match &config.stdin {
+        Some(StdinSource::File(path)) => {
+            let file = fs::File::open(path)
+                .with_context(|| format!("Failed to open stdin file '{}'", path.display()))?;
+            process.stdin(file);
+            None
+        }
+        Some(StdinSource::Arg(arg_name)) => {
+            process.stdin(Stdio::piped());
+            let value = state
+                .arg_values
+                .iter()
+                .rev()
+                .find(|arg_value| &arg_value.arg_name == arg_name)
+                .map(|arg_value| arg_value.value.clone());
+            Some(value.unwrap_or_default())
+        }
+        None => None,
+    };
+
+    if ctx.detach {
+        if config.post_exec.is_some() || config.stdin.is_some() {
+            anyhow::bail!("post_exec and stdin can't be combined with --detach");
+        }
+        let child = spawn_detached(&mut process)?;
+        println!("{}", child.id());
+        Ok(0)
+    } else if config.post_exec.is_some() || config.stdin.is_some() {
+        // The executable has to be waited for or fed, so it runs as a child
+        // instead of replacing clap-bash.
+        let status = run_child(&mut process, &executable, stdin_value.as_deref())?;
+        let mut code = exit_code(status);
+        if let Some(post_exec) = &config.post_exec {
+            if status.success() || config.post_exec_on_failure {
+                let post_status = run_post_exec(post_exec, &process_env)?;
+                if status.success() {
+                    code = exit_code(post_status);
+                }
+            }
+        }
+        Ok(code)
+    } else {
+        launch(ctx, &mut process, &executable)
+    }
+}
+
+/// Replaces clap-bash with the executable, unless `--no-exec` asks to run it
+/// as a child and pass on its exit code instead.
+#[cfg(unix)]
+fn launch(ctx: &RunContext, process: &mut ProcCommand, executable: &Path) -> anyhow::Result<i32> {
+    if ctx.no_exec {
+        return spawn_and_wait(process, executable);
+    }
+    let error = process.exec();
+    Err(error.into())
+}
+
+/// Without `exec` the executable always runs as a child.
+#[cfg(not(unix))]
+fn launch(_ctx: &RunContext, process: &mut ProcCommand, executable: &Path) -> anyhow::Result<i32> {
+    spawn_and_wait(process, executable)
+}
+
+fn spawn_and_wait(process: &mut ProcCommand, executable: &Path) -> anyhow::Result<i32> {
+    let status = run_child(process, executable, None)?;
+    Ok(exit_code(status))
+}
+
+/// Runs the executable as a child, writing `stdin_value` to its piped stdin
+/// if given, and waits for it.
+fn run_child(
+    process: &mut ProcCommand,
+    executable: &Path,
+    stdin_value: Option<&str>,
+) -> anyhow::Result<ExitStatus> {
+    let mut child = process
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", executable.display()))?;
+    if let (Some(value), Some(mut stdin)) = (stdin_value, child.stdin.take()) {
+        // A child that exits without reading its input closes the pipe,
+        // which isn't an error of ours.
+        if let Err(error) = stdin.write_all(value.as_bytes()) {
+            if error.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(error.into());
+            }
+        }
+    }
+    Ok(child.wait()?)
+}
+
+fn run_post_exec(
+    post_exec: &[String],
+    env: &BTreeMap<String, OsString>,
+) -> anyhow::Result<ExitStatus> {
+    let Some((program, post_args)) = post_exec.split_first() else {
+        anyhow::bail!("post_exec must not be empty")
+    };
+    ProcCommand::new(program)
+        .args(post_args)
+        .env_clear()
+        .envs(env)
+        .status()
+        .with_context(|| format!("Failed to run post_exec '{}'", program))
+}
+
+/// Exit code mirroring the status, using the shell's 128 + signal
+/// convention for processes killed by a signal.
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+fn external_args(matches: &ArgMatches) -> Vec<OsString> {
+    matches
+        .get_raw("")
+        .map(|values| values.map(OsStr::to_os_string).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn spawn_detached(process: &mut ProcCommand) -> anyhow::Result<Child> {
+    process.stdin(Stdio::null());
+    // SAFETY: setsid is async-signal-safe and only affects the forked child.
+    unsafe {
+        process.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(process.spawn()?)
+}
+
+/// Without setsid the child only gets its stdin detached.
+#[cfg(not(unix))]
+fn spawn_detached(process: &mut ProcCommand) -> anyhow::Result<Child> {
+    process.stdin(Stdio::null());
+    Ok(process.spawn()?)
+}
+
+fn ordered_arg_values<'a>(ctx: &RunContext, arg_values: &'a [ArgValue]) -> Vec<&'a str> {
+    match &ctx.global_config.positional_order {
+        Some(order) => order
+            .iter()
+            .map(|arg_name| {
+                arg_values
+                    .iter()
+                    .rev()
+                    .find(|arg_value| &arg_value.arg_name == arg_name)
+                    .map_or("", |arg_value| arg_value.value.as_str())
+            })
+            .collect(),
+        None => arg_values
+            .iter()
+            .map(|arg_value| arg_value.value.as_str())
+            .collect(),
+    }
+}
+
+fn resolve_executable(
+    config: &CommandConfig,
+    env: &BTreeMap<String, String>,
+) -> anyhow::Result<PathBuf> {
+    if let Some(resolver) = &config.executable_resolver {
+        run_executable_resolver(resolver, env)
+    } else if let Some(executable) = &config.executable {
+        Ok(executable.clone())
+    } else if let Some(message) = &config.missing_executable_message {
+        anyhow::bail!("{}", message)
+    } else {
+        anyhow::bail!("Missing executable")
+    }
+}
+
+fn run_executable_resolver(
+    resolver: &[String],
+    env: &BTreeMap<String, String>,
+) -> anyhow::Result<PathBuf> {
+    let Some((program, resolver_args)) = resolver.split_first() else {
+        anyhow::bail!("executable_resolver must not be empty")
+    };
+
+    let output = ProcCommand::new(program)
+        .args(resolver_args)
+        .envs(env)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run executable resolver '{}'", program))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Executable resolver '{}' failed with {}",
+            program,
+            output.status
+        );
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let executable = stdout.trim();
+    if executable.is_empty() {
+        anyhow::bail!("Executable resolver '{}' printed no executable", program);
+    }
+    Ok(PathBuf::from(executable))
+}
+
+fn read_env_file(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut env = BTreeMap::new();
+    for entry in dotenvy::from_path_iter(path)
+        .with_context(|| format!("Failed to read env file '{}'", path.display()))?
+    {
+        let (key, value) =
+            entry.with_context(|| format!("Failed to parse env file '{}'", path.display()))?;
+        env.insert(key, value);
+    }
+    Ok(env)
+}
+
+fn resolve_env_layers(ctx: &RunContext, state: &RunState) -> BTreeMap<String, String> {
+    let mut resolved = BTreeMap::new();
+    for layer in ctx.global_config.env_layers.iter().rev() {
+        let layer_env: BTreeMap<String, String> = match layer {
+            EnvLayer::Generated => state.env.clone(),
+            EnvLayer::File => state.file_env.clone(),
+            EnvLayer::Inherited => std::env::vars_os()
+                .map(|(key, value)| {
+                    (
+                        key.to_string_lossy().into_owned(),
+                        value.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect(),
+        };
+
+        if ctx.debug {
+            eprintln!(
+                "[debug] applying env layer {:?} ({} vars)",
+                layer,
+                layer_env.len()
+            );
+        }
+
+        for (key, value) in layer_env {
+            if let Some(previous) = resolved.insert(key.clone(), value) {
+                if ctx.debug {
+                    eprintln!("[debug]   {} overrides previous value {:?}", key, previous);
+                }
+            }
+        }
+    }
+    resolved
+}
+
+/// The resolved env with the raw bytes restored where a value is only the
+/// lossy conversion of them, taken from the generated `raw_env` or the
+/// inherited env, so non-UTF-8 values like paths reach the executable byte
+/// identical. Values that were changed on the way keep their string form.
+fn restore_raw_env(
+    resolved: &BTreeMap<String, String>,
+    state: &RunState,
+) -> BTreeMap<String, OsString> {
+    resolved
+        .iter()
+        .map(|(key, value)| {
+            let raw = state
+                .raw_env
+                .get(key)
+                .cloned()
+                .or_else(|| std::env::var_os(key))
+                .filter(|raw| raw.to_string_lossy() == value.as_str());
+            (key.clone(), raw.unwrap_or_else(|| OsString::from(value)))
+        })
+        .collect()
+}
+
+/// Pairs of distinct names that only collide when compared ignoring ASCII
+/// case, as Windows does for env var names.
+fn case_insensitive_collisions(env: &BTreeMap<String, String>) -> Vec<(&str, &str)> {
+    let mut seen: BTreeMap<String, &str> = BTreeMap::new();
+    let mut collisions = Vec::new();
+    for key in env.keys() {
+        if let Some(previous) = seen.insert(key.to_ascii_uppercase(), key) {
+            collisions.push((previous, key.as_str()));
+        }
+    }
+    collisions
+}
+
+fn login_shell_command(executable: &Path, env: &BTreeMap<String, String>) -> ProcCommand {
+    let mut script = String::new();
+    for (key, value) in env {
+        script.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+    }
+    script.push_str("exec \"$0\" \"$@\"");
+
+    let mut command = ProcCommand::new("bash");
+    command.arg("-lc").arg(script).arg(executable);
+    command
+}
+
+/// The executable and its args as one shell quoted command line.
+fn quote_command(executable: &Path, args: &[OsString]) -> String {
+    let mut line = shell_quote(&executable.to_string_lossy());
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.to_string_lossy()));
+    }
+    line
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// The defined subcommand `name`, `None` for external subcommands.
+fn get_subcommand<'a>(command: &'a Command, name: &str) -> Option<&'a Command> {
+    command.get_subcommands().find(|cmd| cmd.get_name() == name)
+}
+
+fn get_subcommand_config<'a>(config: &'a CommandConfig, name: &str) -> Option<&'a CommandConfig> {
+    for subcommand in config.subcommands.iter() {
+        for (subcommand_name, config) in subcommand.iter() {
+            if subcommand_name == name {
+                return Some(config);
+            }
+        }
+    }
+    None
+}
+
+/// Finds the config of an arg, walking up the ancestors, innermost first, for
+/// args inherited through `propagate_args`. The flag is true for inherited
+/// args, the ancestor defining them exports them.
+fn get_arg_config<'a>(
+    config: &'a CommandConfig,
+    ancestors: &[&'a CommandConfig],
+    command_name: &str,
+    name: &str,
+) -> anyhow::Result<(&'a ArgConfig, bool)> {
+    let own = std::iter::once((config, false));
+    let inherited = ancestors.iter().rev().map(|ancestor| (*ancestor, true));
+    for (config, inherited) in own.chain(inherited) {
+        if let Some(arg_config) = config.args.iter().find_map(|args| args.get(name)) {
+            return Ok((arg_config, inherited));
+        }
+    }
+    anyhow::bail!(
+        "No config found for argument '{}' under command '{}'",
+        name,
+        command_name
+    )
+}
+
+fn create_env_vars(
+    ctx: &RunContext,
+    command: &Command,
+    args: &ArgMatches,
+    config: &CommandConfig,
+    ancestors: &[&CommandConfig],
+    state: &mut RunState,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let prefix = env_var_prefix(ctx, state.depth);
+    let mut mapping = BTreeMap::new();
+    // Which arg produced each var of this command. Collisions are only
+    // checked within one command level, a subcommand may reuse a parent's
+    // name and its value wins.
+    let mut owners = BTreeMap::new();
+    let mut raw_mapping = BTreeMap::new();
+    let mut templated = Vec::new();
+    for arg in command.get_arguments() {
+        let arg_name = arg.get_id().as_str();
+        let (arg_config, inherited) =
+            get_arg_config(config, ancestors, command.get_name(), arg_name)?;
+        if inherited {
+            continue;
+        }
+
+        if let Some(only_for) = &arg_config.only_for {
+            if !matches_subcommand_path(only_for, &ctx.subcommand_path) {
+                continue;
+            }
+        }
+
+        let Some(raw_arg_values) = args.get_raw_occurrences(&arg_name) else {
+            if let Some(template) = &arg_config.default_template {
+                templated.push((arg, arg_config, template));
+            }
+            continue;
+        };
+
+        // clap reports `default_value`s as regular occurrences, so defaults
+        // are exported like passed values, multiple ones joined with the
+        // value delimiter, unless they are opted out of.
+        let export_default = arg_config
+            .export_default
+            .unwrap_or(ctx.global_config.export_defaults && !arg_config.only_if_overridden);
+        if !export_default && args.value_source(arg_name) == Some(ValueSource::DefaultValue) {
+            continue;
+        }
+
+        let ArgEnvVarNames {
+            env_var: env_var_config,
+            index_name,
+            hash_name,
+        } = arg_env_var_names(ctx.global_config, &prefix, command, arg, arg_config)?;
+
+        let occurrences = raw_arg_values
+            .map(|occurence| {
+                occurence
+                    .map(|value| arg_config.transform_value(arg_name, value))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let raw_value = raw_arg_value(args, arg, arg_config, &env_var_config);
+        if let Some(forward) = &arg_config.forward {
+            state
+                .forwarded_args
+                .extend(forward.args(arg, args, &occurrences)?);
+        }
+        let arg_value = match arg_config.reduce {
+            Some(reducer) => reducer.reduce(arg_name, &occurrences.concat())?,
+            None => occurrences
+                .iter()
+                .map(|occurrence| occurrence.join(&env_var_config.value_delimiter))
+                .collect::<Vec<_>>()
+                .join(&env_var_config.occurrence_delimiter),
+        };
+
+        // clap stores flags as raw values too: `SetTrue`/`SetFalse` flags as
+        // `true`/`false` with the opposite as default, so unset flags are
+        // exported as well, and `Count` flags as a single occurrence holding
+        // the final count, `0` by default. Counts are exported as they are.
+        let arg_value = if matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse) {
+            arg_config.format_flag(ctx.bool_format, raw_flag_value(args, arg_name) == "true")
+        } else {
+            arg_value
+        };
+
+        if let (Some(algorithm), Some(hash_name)) = (arg_config.hash, hash_name) {
+            let hash = algorithm.hex_digest(&arg_value);
+            insert_env_var(ctx, &mut mapping, &mut owners, hash_name, hash, arg_name)?;
+        }
+
+        let value = ArgValue {
+            arg_name: arg_name.to_string(),
+            env_var: env_var_config.clone(),
+            value: arg_value.clone(),
+        };
+        if let Some(trace) = &mut state.trace {
+            trace.arg(Some(args), arg_config, &value, state.depth);
+        }
+        state.arg_values.push(value);
+        if let Some(index_name) = index_name {
+            if let Some(raw_value) = &raw_value {
+                raw_mapping.insert(index_name.clone(), raw_value.clone());
+            }
+            let value = arg_value.clone();
+            insert_env_var(ctx, &mut mapping, &mut owners, index_name, value, arg_name)?;
+        }
+        let name = env_var_config.name;
+        if let Some(raw_value) = raw_value {
+            raw_mapping.insert(name.clone(), raw_value);
+        }
+        insert_env_var(ctx, &mut mapping, &mut owners, name, arg_value, arg_name)?;
+    }
+
+    // Templates may reference each other, so resolve them in dependency order
+    // by only expanding templates whose references are all resolved.
+    while !templated.is_empty() {
+        let pending_names = templated
+            .iter()
+            .map(|(arg, arg_config, _)| {
+                let names =
+                    arg_env_var_names(ctx.global_config, &prefix, command, arg, arg_config)?;
+                Ok(names.env_var.name)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let Some(index) = templated.iter().position(|(_, _, template)| {
+            template_vars(template)
+                .iter()
+                .all(|var| !pending_names.contains(var))
+        }) else {
+            let arg_names: Vec<&str> = templated
+                .iter()
+                .map(|(arg, _, _)| arg.get_id().as_str())
+                .collect();
+            anyhow::bail!(
+                "Cyclic default_template references between args: {}",
+                arg_names.join(", ")
+            );
+        };
 
-    #[arg(long)]
-    add_self_to_env: bool,
+        let (arg, arg_config, template) = templated.remove(index);
+        let arg_name = arg.get_id().as_str();
+        let arg_value = expand_template(template, |var| {
+            mapping.get(var).or_else(|| state.env.get(var)).cloned()
+        });
 
-    #[arg(last = true, help = "Arguments passed to the main command")]
-    trailing: Vec<String>,
-}
+        let env_var_config = EnvVarConfig {
+            name: pending_names[index].clone(),
+            ..arg_env_var(ctx.global_config, command, arg, arg_config)?
+        };
+        let value = ArgValue {
+            arg_name: arg_name.to_string(),
+            env_var: env_var_config.clone(),
+            value: arg_value.clone(),
+        };
+        if let Some(trace) = &mut state.trace {
+            trace.arg(None, arg_config, &value, state.depth);
+        }
+        state.arg_values.push(value);
+        let name = env_var_config.name;
+        insert_env_var(ctx, &mut mapping, &mut owners, name, arg_value, arg_name)?;
+    }
 
-#[derive(Debug)]
-struct Config {
-    clap_config: Command,
-    command_config: CommandConfig,
+    for group in &config.argv_groups {
+        if let Some(argv) = group_argv(command, args, &group.args) {
+            let name = final_env_var_name(ctx.global_config, &prefix, &group.env_var, false)?;
+            let owner = format!("argv group {}", group.env_var);
+            insert_env_var(ctx, &mut mapping, &mut owners, name, argv, &owner)?;
+        }
+    }
+
+    if let Some(provided_env_var) = ctx.global_config.provided_env_var.clone() {
+        let provided_env_var = provided_env_var.into_config();
+        let provided = command
+            .get_arguments()
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| args.value_source(id) == Some(ValueSource::CommandLine))
+            .collect::<Vec<_>>()
+            .join(&provided_env_var.value_delimiter);
+        let name = final_env_var_name(
+            ctx.global_config,
+            &prefix,
+            &ctx.global_config.reserved_env_var(&provided_env_var.name),
+            false,
+        )?;
+        insert_env_var(
+            ctx,
+            &mut mapping,
+            &mut owners,
+            name,
+            provided,
+            "provided_env_var",
+        )?;
+    }
+
+    // Raw values of a parent level are stale once this level sets the var.
+    state.raw_env.retain(|name, _| !mapping.contains_key(name));
+    state.raw_env.extend(raw_mapping);
+
+    Ok(mapping)
 }
 
-#[derive(Debug, Deserialize)]
-struct CommandConfig {
-    executable: Option<PathBuf>,
+/// Last raw value clap matched for an arg, for flags `true`/`false` or the
+/// count, before any transform could have changed it.
+fn raw_flag_value(args: &ArgMatches, arg_name: &str) -> String {
+    args.get_raw(arg_name)
+        .and_then(|values| values.last())
+        .map(|value| value.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
 
-    #[serde(default)]
-    args: Vec<HashMap<String, ArgConfig>>,
+/// The exported value of an arg with non-UTF-8 values as raw bytes, joined
+/// with its delimiters like the string value. Only args exported unchanged
+/// qualify, every transform works on strings and keeps the lossy value.
+fn raw_arg_value(
+    args: &ArgMatches,
+    arg: &Arg,
+    arg_config: &ArgConfig,
+    env_var: &EnvVarConfig,
+) -> Option<OsString> {
+    let occurrences: Vec<Vec<&OsStr>> = args
+        .get_raw_occurrences(arg.get_id().as_str())?
+        .map(Iterator::collect)
+        .collect();
+    let is_utf8 = occurrences
+        .iter()
+        .flatten()
+        .all(|value| value.to_str().is_some());
+    let unchanged = arg_config.transform_names().is_empty()
+        && arg_config.reduce.is_none()
+        && !matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse);
+    if is_utf8 || !unchanged {
+        return None;
+    }
 
-    #[serde(default)]
-    subcommands: Vec<HashMap<String, CommandConfig>>,
+    let mut raw = OsString::new();
+    for (i, occurrence) in occurrences.iter().enumerate() {
+        if i > 0 {
+            raw.push(&env_var.occurrence_delimiter);
+        }
+        for (j, value) in occurrence.iter().enumerate() {
+            if j > 0 {
+                raw.push(&env_var.value_delimiter);
+            }
+            raw.push(value);
+        }
+    }
+    Some(raw)
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct EnvVarConfig {
+/// Inserts a var generated for `owner`, failing if a different arg of the
+/// same command already generated it, unless `--allow-env-collisions` was
+/// passed, which keeps the last value.
+fn insert_env_var(
+    ctx: &RunContext,
+    mapping: &mut BTreeMap<String, String>,
+    owners: &mut BTreeMap<String, String>,
     name: String,
-    #[serde(default = "default_value_delimiter")]
-    value_delimiter: String,
-    #[serde(default = "default_occurrence_delimiter")]
-    occurrence_delimiter: String,
+    value: String,
+    owner: &str,
+) -> anyhow::Result<()> {
+    if let Some(previous) = owners.get(&name) {
+        if previous != owner && !ctx.allow_env_collisions {
+            anyhow::bail!(
+                "Args '{}' and '{}' both map to the env var {}, rename one with env_var or pass --allow-env-collisions",
+                previous,
+                owner,
+                name
+            );
+        }
+    }
+    owners.insert(name.clone(), owner.to_string());
+    mapping.insert(name, value);
+    Ok(())
 }
 
-fn default_value_delimiter() -> String {
-    ";".to_string()
+/// Every name one arg is exported under, shared by the export and
+/// `list-env-vars` so both agree.
+struct ArgEnvVarNames {
+    /// The main var with its final name.
+    env_var: EnvVarConfig,
+    /// The positional index companion, only with `positional_env_vars` `both`.
+    index_name: Option<String>,
+    /// The companion holding the hash, if the arg has `hash` set.
+    hash_name: Option<String>,
 }
-fn default_occurrence_delimiter() -> String {
-    ",".to_string()
+
+/// Names of an arg with the level `prefix` and `env_var_name_limit` applied.
+/// With `positional_env_vars` `index` positionals are exported under their
+/// index name only, with `both` under their regular name and the index name.
+fn arg_env_var_names(
+    global_config: &GlobalConfig,
+    prefix: &str,
+    command: &Command,
+    arg: &Arg,
+    arg_config: &ArgConfig,
+) -> anyhow::Result<ArgEnvVarNames> {
+    let mut env_var = arg_env_var(global_config, command, arg, arg_config)?;
+    let mut explicit = arg_config.env_var.is_some();
+    let mut index_name = positional_index(command, arg).map(|index| {
+        format!(
+            "{}{}{}",
+            global_config.env_prefix.as_deref().unwrap_or(""),
+            global_config.positional_index_prefix,
+            index
+        )
+    });
+    if global_config.positional_env_vars == PositionalEnvVars::Index {
+        if let Some(index_name) = index_name.take() {
+            env_var.name = index_name;
+            explicit = false;
+        }
+    }
+
+    let hash_name = arg_config
+        .hash
+        .map(|algorithm| {
+            let hash_name = format!("{}_{}", env_var.name, algorithm.suffix());
+            final_env_var_name(global_config, prefix, &hash_name, false)
+        })
+        .transpose()?;
+    let index_name = index_name
+        .filter(|_| global_config.positional_env_vars == PositionalEnvVars::Both)
+        .map(|name| final_env_var_name(global_config, prefix, &name, false))
+        .transpose()?;
+    env_var.name = final_env_var_name(global_config, prefix, &env_var.name, explicit)?;
+    Ok(ArgEnvVarNames {
+        env_var,
+        index_name,
+        hash_name,
+    })
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
-enum EnvVar {
-    Simple(String),
-    Full(EnvVarConfig),
+/// clap's 1-based index of a positional arg. Positionals without an explicit
+/// `index` are numbered in definition order, like clap does when building.
+fn positional_index(command: &Command, arg: &Arg) -> Option<usize> {
+    if !arg.is_positional() {
+        return None;
+    }
+    arg.get_index().or_else(|| {
+        command
+            .get_positionals()
+            .position(|positional| positional.get_id() == arg.get_id())
+            .map(|position| position + 1)
+    })
 }
 
-impl EnvVar {
-    fn into_config(self) -> EnvVarConfig {
-        match self {
-            EnvVar::Simple(name) => EnvVarConfig {
-                name,
-                value_delimiter: default_value_delimiter(),
-                occurrence_delimiter: default_occurrence_delimiter(),
-            },
-            EnvVar::Full(cfg) => cfg,
+/// Reconstructs the command line tokens of the member args, ordered by their
+/// position in argv. Values that never were on the command line, like
+/// defaults, are left out. Returns `None` if no member was given.
+fn group_argv(command: &Command, args: &ArgMatches, members: &[String]) -> Option<String> {
+    let mut occurrences: Vec<(usize, Vec<String>)> = Vec::new();
+    for arg in command.get_arguments() {
+        let arg_name = arg.get_id().as_str();
+        if !members.iter().any(|member| member == arg_name)
+            || args.value_source(arg_name) != Some(ValueSource::CommandLine)
+        {
+            continue;
+        }
+        let Some(mut indices) = args.indices_of(arg_name) else {
+            continue;
+        };
+
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => Some(format!("--{}", long)),
+            (None, Some(short)) => Some(format!("-{}", short)),
+            (None, None) => None,
+        };
+
+        if !arg.get_action().takes_values() {
+            occurrences.extend(indices.map(|index| (index, flag.iter().cloned().collect())));
+            continue;
+        }
+
+        let Some(raw_arg_values) = args.get_raw_occurrences(arg_name) else {
+            continue;
+        };
+        for occurrence in raw_arg_values {
+            let values: Vec<String> = occurrence
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            let index = indices.by_ref().take(values.len()).min().unwrap_or(0);
+            let mut tokens: Vec<String> = flag.iter().cloned().collect();
+            tokens.extend(values);
+            occurrences.push((index, tokens));
         }
     }
+
+    if occurrences.is_empty() {
+        return None;
+    }
+    occurrences.sort_by_key(|(index, _)| *index);
+    let tokens: Vec<String> = occurrences
+        .into_iter()
+        .flat_map(|(_, tokens)| tokens)
+        .map(|token| shell_quote(&token))
+        .collect();
+    Some(tokens.join(" "))
 }
 
-#[derive(Debug, Deserialize)]
-struct ArgConfig {
-    env_var: Option<EnvVar>,
+/// Names of the vars referenced as `${NAME}` in a template.
+fn template_vars(template: &str) -> Vec<String> {
+    let mut vars = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        vars.push(rest[start + 2..start + end].to_string());
+        rest = &rest[start + end + 1..];
+    }
+    vars
 }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+/// Replaces every `${NAME}` in a template with the looked up value, or an
+/// empty string if there is none.
+fn expand_template(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&lookup(&rest[start + 2..start + end]).unwrap_or_default());
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
 
-    let json_data = if let Some(json) = cli.json {
-        json
-    } else if let Some(file) = cli.json_file {
-        fs::read_to_string(file).expect("Failed to read JSON file")
+fn env_var_prefix(ctx: &RunContext, depth: usize) -> String {
+    if ctx.level_prefix {
+        format!("L{}{}", depth, ctx.global_config.namespace_separator)
     } else {
-        anyhow::bail!("You must provide either --json or --json-file")
+        String::new()
+    }
+}
+
+fn run_transform_cmd(
+    transform_cmd: &[String],
+    arg_name: &str,
+    value: &str,
+) -> anyhow::Result<String> {
+    let Some((program, transform_args)) = transform_cmd.split_first() else {
+        anyhow::bail!("transform_cmd of arg '{}' must not be empty", arg_name)
     };
 
-    let config: Config = serde_json::from_str(&json_data)?;
+    let mut child = ProcCommand::new(program)
+        .args(transform_args)
+        .env("CLAP_BASH_ARG", arg_name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to run transform_cmd of arg '{}'", arg_name))?;
 
-    let app = config.clap_config;
-    let command_config = config.command_config;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(value.as_bytes())
+            .with_context(|| format!("Failed to write to transform_cmd of arg '{}'", arg_name))?;
+    }
 
-    let mut args = cli.trailing;
-    let app_name = app.get_name();
-    args.insert(0, app_name.to_string());
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to run transform_cmd of arg '{}'", arg_name))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "transform_cmd of arg '{}' failed with {}",
+            arg_name,
+            output.status
+        );
+    }
 
-    let matches = app.clone().get_matches_from(args);
-    let mut env = BTreeMap::new();
+    let transformed = String::from_utf8(output.stdout)
+        .with_context(|| format!("transform_cmd of arg '{}' printed invalid UTF-8", arg_name))?;
+    Ok(transformed.trim_end_matches('\n').to_string())
+}
 
-    if cli.add_self_to_env {
-        if let Ok(exe) = std::env::current_exe() {
-            if let Some(exe_str) = exe.to_str() {
-                env.insert("self".to_string(), exe_str.to_string());
-            }
-        };
-    };
+const PATH_LIST_SEPARATOR: &str = if cfg!(windows) { ";" } else { ":" };
+
+fn normalize_path_list(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        // Keep the colon of Windows drive letters like `C:\` inside the entry.
+        let is_drive_colon = c == ':'
+            && current.len() == 1
+            && current.starts_with(|c: char| c.is_ascii_alphabetic())
+            && matches!(chars.get(i + 1), Some('\\' | '/'));
 
-    run(&app, &matches, &command_config, env)
+        if (c == ':' || c == ';') && !is_drive_colon {
+            entries.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    entries.push(current);
+    entries.join(PATH_LIST_SEPARATOR)
 }
 
-fn run(
+fn check_delimiters(config: &Config) -> anyhow::Result<()> {
+    let conflicts = delimiter_conflicts(
+        &config.global_config,
+        &config.clap_config,
+        &config.command_config,
+    );
+    if !conflicts.is_empty() {
+        let conflicts: Vec<String> = conflicts
+            .iter()
+            .map(|(path, conflict)| format!("{}: {}", path, conflict))
+            .collect();
+        anyhow::bail!("Conflicting delimiters:\n  {}", conflicts.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Delimiter conflicts of the whole tree as `(command path, conflict)`.
+/// Every arg is checked with the delimiters it is exported with, defaults
+/// included, and a var exported on several nesting levels has to be joined
+/// the same way on all of them, since a script splitting it can't tell
+/// which level set it.
+fn delimiter_conflicts(
+    global_config: &GlobalConfig,
     command: &Command,
-    args: &ArgMatches,
     config: &CommandConfig,
-    mut env: BTreeMap<String, String>,
-) -> anyhow::Result<()> {
-    let env_vars = create_env_vars(command, args, config);
-    env.extend(env_vars);
+) -> Vec<(String, String)> {
+    let mut conflicts = Vec::new();
+    collect_delimiter_conflicts(
+        global_config,
+        command,
+        config,
+        command.get_name(),
+        &BTreeMap::new(),
+        &mut conflicts,
+    );
+    conflicts
+}
 
-    if let Some((name, subargs)) = args.subcommand() {
-        let subconfig = get_subcommand_config(config, name);
-        let subcommand = get_subcommand(command, name);
+fn collect_delimiter_conflicts(
+    global_config: &GlobalConfig,
+    command: &Command,
+    config: &CommandConfig,
+    path: &str,
+    ancestor_vars: &BTreeMap<String, (String, EnvVarConfig)>,
+    conflicts: &mut Vec<(String, String)>,
+) {
+    let mut exported = ancestor_vars.clone();
+    for arg in command.get_arguments() {
+        let arg_name = arg.get_id().as_str();
+        let Some(arg_config) = config.args.iter().find_map(|args| args.get(arg_name)) else {
+            continue;
+        };
+        // Missing configs and invalid names are reported on their own.
+        let Ok(env_var) = arg_env_var(global_config, command, arg, arg_config) else {
+            continue;
+        };
 
-        run(subcommand, subargs, subconfig, env)
-    } else {
-        if let Some(executable) = &config.executable {
-            let error = ProcCommand::new(executable).envs(env).exec();
-            Err(error.into())
-        } else {
-            anyhow::bail!("Missing executable")
+        if let Some(conflict) = delimiter_conflict(arg, &env_var, arg_config) {
+            conflicts.push((
+                path.to_string(),
+                format!("arg '{}': {}", arg_name, conflict),
+            ));
+        }
+        if let Some((ancestor, ancestor_env_var)) = ancestor_vars.get(&env_var.name) {
+            let differs = ancestor_env_var.value_delimiter != env_var.value_delimiter
+                || ancestor_env_var.occurrence_delimiter != env_var.occurrence_delimiter;
+            if differs {
+                conflicts.push((
+                    path.to_string(),
+                    format!(
+                        "arg '{}' exports {} with delimiters '{}'/'{}', {} with '{}'/'{}'",
+                        arg_name,
+                        env_var.name,
+                        env_var.value_delimiter,
+                        env_var.occurrence_delimiter,
+                        ancestor,
+                        ancestor_env_var.value_delimiter,
+                        ancestor_env_var.occurrence_delimiter
+                    ),
+                ));
+            }
         }
+        let owner = format!("arg '{}' of '{}'", arg_name, path);
+        exported.insert(env_var.name.clone(), (owner, env_var));
     }
-}
 
-fn get_subcommand<'a>(command: &'a Command, name: &str) -> &'a Command {
-    command
-        .get_subcommands()
-        .find(|cmd| cmd.get_name() == name)
-        .expect("Missing subcommand")
+    for subcommand in command.get_subcommands() {
+        let name = subcommand.get_name();
+        if let Some(subconfig) = get_subcommand_config(config, name) {
+            collect_delimiter_conflicts(
+                global_config,
+                subcommand,
+                subconfig,
+                &format!("{} {}", path, name),
+                &exported,
+                conflicts,
+            );
+        }
+    }
 }
 
-fn get_subcommand_config<'a>(config: &'a CommandConfig, name: &str) -> &'a CommandConfig {
-    for subcommand in config.subcommands.iter() {
-        for (subcommand_name, config) in subcommand.iter() {
-            if subcommand_name == name {
-                return config;
-            }
+/// Conflicts between the delimiters of one arg, checked with the effective
+/// env var config, so the default delimiters count as well. The path list
+/// separator is only compared with the delimiters the arg can actually
+/// join with, on Windows it equals the default `value_delimiter`.
+fn delimiter_conflict(arg: &Arg, env_var: &EnvVarConfig, arg_config: &ArgConfig) -> Option<String> {
+    let value_delimiter = ("value_delimiter", env_var.value_delimiter.as_str());
+    let occurrence_delimiter = (
+        "occurrence_delimiter",
+        env_var.occurrence_delimiter.as_str(),
+    );
+    let mut pairs = vec![(value_delimiter, occurrence_delimiter)];
+    if arg_config.path_list {
+        let separator = ("path list separator", PATH_LIST_SEPARATOR);
+        if arg
+            .get_num_args()
+            .is_some_and(|num_args| num_args.max_values() > 1)
+        {
+            pairs.push((value_delimiter, separator));
+        }
+        if matches!(arg.get_action(), ArgAction::Append) {
+            pairs.push((occurrence_delimiter, separator));
         }
     }
-    todo!()
+
+    pairs
+        .into_iter()
+        .find(|((_, first), (_, second))| delimiters_conflict(first, second))
+        .map(|((first_name, first), (second_name, second))| {
+            format!(
+                "{} '{}' and {} '{}'",
+                first_name, first, second_name, second
+            )
+        })
+}
+
+/// Two non-empty delimiters are ambiguous when one contains the other, since
+/// the joined value can then no longer be split back unambiguously.
+fn delimiters_conflict(first: &str, second: &str) -> bool {
+    !first.is_empty() && !second.is_empty() && (first.contains(second) || second.contains(first))
+}
+
+fn matches_subcommand_path(only_for: &[String], path: &[String]) -> bool {
+    only_for.iter().any(|entry| {
+        let entry: Vec<&str> = entry.split_whitespace().collect();
+        entry.len() <= path.len() && entry.iter().zip(path).all(|(a, b)| a == b)
+    })
 }
 
-fn get_arg_config<'a>(config: &'a CommandConfig, name: &str) -> &'a ArgConfig {
-    for arg in config.args.iter() {
-        for (arg_name, config) in arg.iter() {
-            if arg_name == name {
-                return config;
+/// Env var of an arg without the namespace prefix. Explicit names are taken
+/// as they are, derived and `env_var_format` names get the `env_prefix`.
+/// `env_var_name_limit` applies later, see `final_env_var_name`.
+fn arg_env_var(
+    global_config: &GlobalConfig,
+    command: &Command,
+    arg: &Arg,
+    arg_config: &ArgConfig,
+) -> anyhow::Result<EnvVarConfig> {
+    let arg_name = arg.get_id().as_str();
+    let env_var = match arg_config.env_var.clone() {
+        Some(env_var) => {
+            let name = env_var.clone().into_config().name;
+            if !is_posix_identifier(&name) {
+                anyhow::bail!(
+                    "Env var name '{}' of arg '{}' is not a valid identifier ([A-Za-z_][A-Za-z0-9_]*)",
+                    name,
+                    arg_name
+                );
             }
+            env_var
         }
-    }
-    todo!()
+        None => {
+            let env_var_name = match &arg_config.env_var_format {
+                Some(env_var_format) => format_env_var_name(env_var_format, command, arg)?,
+                None => to_env_var_name(arg_name),
+            };
+            EnvVar::Simple(format!(
+                "{}{}",
+                global_config.env_prefix.as_deref().unwrap_or(""),
+                env_var_name
+            ))
+        }
+    };
+    Ok(env_var.into_config())
 }
 
-fn create_env_vars(
+/// Expands an `env_var_format` like `"OPT_{ID}"`. Placeholders are `{id}`,
+/// `{ID}` (the id as derived env var name), `{kind}` (`flag`, `option` or
+/// `positional`) and `{index}` (1-based positional index, empty otherwise).
+/// `{{` and `}}` are literal braces.
+fn format_env_var_name(
+    env_var_format: &str,
     command: &Command,
-    args: &ArgMatches,
-    config: &CommandConfig,
-) -> BTreeMap<String, String> {
-    let mut mapping = BTreeMap::new();
-    for arg in command.get_arguments() {
-        let arg_name = arg.get_id().as_str();
-        let Some(raw_arg_values) = args.get_raw_occurrences(&arg_name) else {
-            continue;
-        };
+    arg: &Arg,
+) -> anyhow::Result<String> {
+    let arg_name = arg.get_id().as_str();
+    let mut name = String::new();
+    let mut chars = env_var_format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                name.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                name.push('}');
+            }
+            '{' => {
+                let Some((placeholder, rest)) = chars.as_str().split_once('}') else {
+                    anyhow::bail!(
+                        "Unclosed '{{' in env_var_format '{}' of arg '{}'",
+                        env_var_format,
+                        arg_name
+                    );
+                };
+                match placeholder {
+                    "id" => name.push_str(arg_name),
+                    "ID" => name.push_str(&to_env_var_name(arg_name)),
+                    "kind" => name.push_str(if arg.is_positional() {
+                        "positional"
+                    } else if arg.get_action().takes_values() {
+                        "option"
+                    } else {
+                        "flag"
+                    }),
+                    "index" => {
+                        if let Some(index) = positional_index(command, arg) {
+                            name.push_str(&index.to_string());
+                        }
+                    }
+                    _ => anyhow::bail!(
+                        "Unknown placeholder '{{{}}}' in env_var_format '{}' of arg '{}'",
+                        placeholder,
+                        env_var_format,
+                        arg_name
+                    ),
+                }
+                chars = rest.chars();
+            }
+            '}' => anyhow::bail!(
+                "Unmatched '}}' in env_var_format '{}' of arg '{}'",
+                env_var_format,
+                arg_name
+            ),
+            c => name.push(c),
+        }
+    }
 
-        let arg_config = get_arg_config(config, arg_name);
+    if !is_posix_identifier(&name) {
+        anyhow::bail!(
+            "env_var_format '{}' of arg '{}' produced '{}', which is not a valid identifier",
+            env_var_format,
+            arg_name,
+            name
+        );
+    }
+    Ok(name)
+}
 
-        let env_var_config = arg_config
-            .env_var
-            .clone()
-            .unwrap_or_else(|| {
-                let env_var_name = to_env_var_name(arg_name);
-                EnvVar::Simple(env_var_name)
-            })
-            .into_config();
+fn is_posix_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-        let arg_value = raw_arg_values
-            .map(|occurence| {
-                occurence
-                    .map(|value| value.to_string_lossy())
-                    .collect::<Vec<_>>()
-                    .join(&env_var_config.value_delimiter)
-            })
-            .collect::<Vec<_>>()
-            .join(&env_var_config.occurrence_delimiter);
+/// Adds the level prefix to a name and applies `env_var_name_limit` to the
+/// result, so the limit holds for every name that is actually exported,
+/// including hash companions and positional index names. Explicit names are
+/// exempt as long as they are exported exactly as configured.
+fn final_env_var_name(
+    global_config: &GlobalConfig,
+    prefix: &str,
+    name: &str,
+    explicit: bool,
+) -> anyhow::Result<String> {
+    let name = format!("{}{}", prefix, name);
+    match &global_config.env_var_name_limit {
+        Some(limit) if !explicit || !prefix.is_empty() => limit_env_var_name(name, limit),
+        _ => Ok(name),
+    }
+}
+
+fn limit_env_var_name(name: String, limit: &EnvVarNameLimit) -> anyhow::Result<String> {
+    if name.len() <= limit.max_length {
+        return Ok(name);
+    }
 
-        mapping.insert(env_var_config.name, arg_value);
+    match limit.policy {
+        EnvVarNameLimitPolicy::Error => anyhow::bail!(
+            "Env var name '{}' is longer than the maximum of {} characters",
+            name,
+            limit.max_length
+        ),
+        EnvVarNameLimitPolicy::Truncate => {
+            let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+            let Some(keep) = limit.max_length.checked_sub(9).filter(|keep| *keep > 0) else {
+                anyhow::bail!(
+                    "A maximum env var name length of {} is too short to truncate '{}'",
+                    limit.max_length,
+                    name
+                )
+            };
+            // Lengths are in bytes, cut before a character that doesn't fit
+            // whole, names from `static_env` or env files may be non-ASCII.
+            let keep = (0..=keep)
+                .rev()
+                .find(|end| name.is_char_boundary(*end))
+                .unwrap_or_default();
+            Ok(format!("{}_{}", &name[..keep], &hash[..8]))
+        }
     }
-    mapping
 }
 
 fn to_env_var_name(input: &str) -> String {
@@ -237,48 +3033,313 @@ impl<'de> Deserialize<'de> for Config {
     where
         D: Deserializer<'de>,
     {
-        let mut full_json = Value::deserialize(deserializer)?;
-        let runtime_json = extract_runtime(&mut full_json);
+        let full_json = Value::deserialize(deserializer)?;
+        let (config, problems) = Config::from_json(full_json).map_err(serde::de::Error::custom)?;
+        if !problems.is_empty() {
+            return Err(serde::de::Error::custom(format!(
+                "Invalid config:\n  {}",
+                problems.join("\n  ")
+            )));
+        }
+
+        let is_empty = config.clap_config.get_arguments().next().is_none()
+            && config.clap_config.get_subcommands().next().is_none()
+            && config.command_config.executable.is_none()
+            && config.command_config.executable_resolver.is_none()
+            && !config.command_config.external_passthrough;
+        if is_empty {
+            return Err(serde::de::Error::custom(
+                "The config defines no runnable command, it needs an executable, args or subcommands",
+            ));
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Builds the config and collects the problems found on the way instead
+    /// of failing on the first, so `--validate --all` can report them with
+    /// its own. Only problems that leave no config behind are an error.
+    fn from_json(mut full_json: Value) -> anyhow::Result<(Config, Vec<String>)> {
+        let (global_json, runtime_json) = split_config(&mut full_json)?;
+
+        let mut problems = clap_check::clap_config_problems(&full_json);
+        let clap_config = serde_json::to_string_pretty(&full_json)?;
+        let clap_config: CommandWrap = match serde_json::from_str(&clap_config) {
+            Ok(clap_config) => clap_config,
+            Err(error) => {
+                problems.push(format!("Invalid clap config: {}", error));
+                anyhow::bail!("{}", problems.join("\n"));
+            }
+        };
+
+        let command_config: CommandConfig = serde_json::from_value(runtime_json)?;
 
-        let clap_config =
-            serde_json::to_string_pretty(&full_json).map_err(serde::de::Error::custom)?;
-        let clap_config: CommandWrap = serde_json::from_str(&clap_config).unwrap();
+        let global_config: GlobalConfig = serde_json::from_value(global_json)?;
+        if let Some(env_prefix) = &global_config.env_prefix {
+            let valid = env_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !env_prefix.starts_with(|c: char| c.is_ascii_digit());
+            if !valid {
+                problems.push(format!(
+                    "env_prefix '{}' may only contain A-Z, a-z, 0-9 and _ and must not start with a digit",
+                    env_prefix
+                ));
+            }
+        }
 
-        let command_config: CommandConfig =
-            serde_json::from_value(runtime_json).map_err(serde::de::Error::custom)?;
+        let clap_config = apply_clap_settings(clap_config.into(), &command_config);
 
-        Ok(Config {
-            clap_config: clap_config.into(),
+        let mut stray = Vec::new();
+        stray_arg_configs(
+            &clap_config,
+            &command_config,
+            clap_config.get_name(),
+            &mut stray,
+        );
+        problems.extend(
+            stray
+                .into_iter()
+                .map(|entry| format!("{} has no matching clap argument", entry)),
+        );
+
+        let config = Config {
+            clap_config,
             command_config,
-        })
+            global_config,
+        };
+        Ok((config, problems))
+    }
+}
+
+/// Collects the runtime arg entries and `argv_groups` members of the whole
+/// tree that no clap arg of their command uses, since `create_env_vars` only
+/// looks at clap's args and would silently ignore them.
+fn stray_arg_configs(
+    command: &Command,
+    config: &CommandConfig,
+    path: &str,
+    stray: &mut Vec<String>,
+) {
+    for name in config.args.iter().flat_map(|args| args.keys()) {
+        if !command
+            .get_arguments()
+            .any(|arg| arg.get_id().as_str() == name)
+        {
+            stray.push(format!("{}: argument '{}'", path, name));
+        }
+    }
+    for group in &config.argv_groups {
+        for member in group.unknown_members(command) {
+            stray.push(format!(
+                "{}: argv group {} member '{}'",
+                path, group.env_var, member
+            ));
+        }
+    }
+
+    for subcommand in command.get_subcommands() {
+        if let Some(subconfig) = get_subcommand_config(config, subcommand.get_name()) {
+            let subpath = format!("{} {}", path, subcommand.get_name());
+            stray_arg_configs(subcommand, subconfig, &subpath, stray);
+        }
+    }
+}
+
+/// Subcommand nesting allowed unless the config sets `max_depth`.
+const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Splits the global and runtime keys off the config, leaving only the clap
+/// part behind. Returns `(global, runtime)`.
+fn split_config(full_json: &mut Value) -> anyhow::Result<(Value, Value)> {
+    let global_json = extract_global(full_json);
+    let max_depth = match global_json.get("max_depth") {
+        Some(max_depth) => serde_json::from_value(max_depth.clone())
+            .context("max_depth must be a non-negative integer")?,
+        None => DEFAULT_MAX_DEPTH,
+    };
+    check_depth(full_json, 0, max_depth)?;
+
+    propagate_args(full_json)?;
+    let runtime_json = extract_runtime(full_json)?;
+    Ok((global_json, runtime_json))
+}
+
+/// Guards the recursive passes over the config against runaway nesting.
+fn check_depth(v: &Value, depth: usize, max_depth: usize) -> anyhow::Result<()> {
+    if depth > max_depth {
+        anyhow::bail!(
+            "Subcommands are nested deeper than max_depth ({})",
+            max_depth
+        );
+    }
+    if let Some(Value::Array(subcommands)) = v.get("subcommands") {
+        for subcommand in subcommands {
+            if let Value::Object(subcommand) = subcommand {
+                for value in subcommand.values() {
+                    check_depth(value, depth + 1, max_depth)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_clap_settings(mut command: Command, config: &CommandConfig) -> Command {
+    if let Some(clap_settings) = &config.clap_settings {
+        command = clap_settings.apply(command);
+    }
+    // Values are only ever read raw, so let non-UTF-8 ones through instead
+    // of rejecting them with clap's default `String` parser. Args with
+    // possible values keep their parser, it does the checking.
+    command = command.mut_args(|arg| {
+        let is_default_parser = arg.get_action().takes_values()
+            && arg.get_value_parser().type_id() == std::any::TypeId::of::<String>()
+            && arg.get_possible_values().is_empty();
+        if is_default_parser {
+            arg.value_parser(clap::value_parser!(OsString))
+        } else {
+            arg
+        }
+    });
+    for subcommands in config.subcommands.iter() {
+        for (name, subconfig) in subcommands.iter() {
+            command = command.mut_subcommand(name, |subcommand| {
+                apply_clap_settings(subcommand, subconfig)
+            });
+        }
+    }
+    command
+}
+
+/// Marks the args of every command with `"propagate_args": true` as clap
+/// `global` args, so all descendants accept them without copies of their
+/// clap or runtime config. clap propagates values passed to a descendant up
+/// to the defining command, which exports them once, descendants resolve
+/// the config through `get_arg_config`'s walk up the ancestors.
+fn propagate_args(v: &mut Value) -> anyhow::Result<()> {
+    let Value::Object(map) = v else {
+        return Ok(());
+    };
+
+    if matches!(map.remove("propagate_args"), Some(Value::Bool(true))) {
+        if let Some(Value::Array(args)) = map.get_mut("args") {
+            for (name, arg) in args
+                .iter_mut()
+                .filter_map(Value::as_object_mut)
+                .flat_map(|arg| arg.iter_mut())
+            {
+                let Value::Object(arg) = arg else {
+                    continue;
+                };
+                if arg.get("required") == Some(&Value::Bool(true)) {
+                    anyhow::bail!(
+                        "Can't propagate the required arg '{}', clap doesn't allow required global args",
+                        name
+                    );
+                }
+                arg.entry("global").or_insert(Value::Bool(true));
+            }
+        }
+    }
+
+    if let Some(Value::Array(subcommands)) = map.get_mut("subcommands") {
+        for subcommand in subcommands.iter_mut() {
+            if let Value::Object(subcommand) = subcommand {
+                for value in subcommand.values_mut() {
+                    propagate_args(value)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+const GLOBAL_KEYS: &[&str] = &[
+    "env_layers",
+    "provided_env_var",
+    "export_defaults",
+    "env_prefix",
+    "subcommand_env_var",
+    "env_var_name_limit",
+    "positional_order",
+    "positional_env_vars",
+    "positional_index_prefix",
+    "namespace_separator",
+    "max_depth",
+];
+
+fn extract_global(v: &mut Value) -> Value {
+    let mut global_map = Map::new();
+    if let Value::Object(map) = v {
+        for &key in GLOBAL_KEYS {
+            if let Some(val) = map.remove(key) {
+                global_map.insert(key.to_string(), val);
+            }
+        }
     }
+    Value::Object(global_map)
 }
 
-fn extract_runtime(v: &mut Value) -> Value {
+const RUNTIME_KEYS: &[&str] = &[
+    "executable",
+    "exec_args",
+    "executable_resolver",
+    "missing_executable_message",
+    "missing_subcommand_message",
+    "env_file",
+    "external_passthrough",
+    "clap_settings",
+    "stdin",
+    "post_exec",
+    "post_exec_on_failure",
+    "argv_groups",
+    "static_env",
+    "env_var",
+    "env_var_format",
+    "login_shell",
+    "hash",
+    "only_for",
+    "read_from_file",
+    "keep_trailing_newline",
+    "expand_tilde",
+    "strip_prefix",
+    "strip_suffix",
+    "path_list",
+    "transform_cmd",
+    "transform",
+    "value_type",
+    "min",
+    "max",
+    "range_message",
+    "flag_true",
+    "flag_false",
+    "reduce",
+    "default_template",
+    "complete_cmd",
+    "encode",
+    "only_if_overridden",
+    "export_default",
+    "forward",
+];
+
+fn extract_runtime(v: &mut Value) -> anyhow::Result<Value> {
     match v {
         Value::Object(map) => {
             let mut runtime_map = serde_json::Map::new();
 
-            for key in ["executable", "env_var"] {
+            for &key in RUNTIME_KEYS {
                 if let Some(val) = map.remove(key) {
                     runtime_map.insert(key.to_string(), val);
                 }
             }
 
             if let Some(Value::Array(args)) = map.get_mut("args") {
-                let runtime_args: Vec<Value> = args
+                let runtime_args = args
                     .iter_mut()
-                    .map(|arg| match arg {
-                        Value::Object(object) => {
-                            let (name, value) = object.iter_mut().next().unwrap();
-                            let value = extract_runtime(value);
-                            let mut map = Map::new();
-                            map.insert(name.to_string(), value);
-                            Value::Object(map)
-                        }
-                        _ => todo!(),
-                    })
-                    .collect();
+                    .map(|arg| extract_named_runtime(arg, "argument"))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
                 if !runtime_args.is_empty() {
                     runtime_map.insert("args".to_string(), Value::Array(runtime_args));
                 }
@@ -287,32 +3348,42 @@ fn extract_runtime(v: &mut Value) -> Value {
             if let Some(subs) = map.get_mut("subcommands") {
                 match subs {
                     Value::Array(arr) => {
-                        let runtime_subs: Vec<Value> = arr
+                        let runtime_subs = arr
                             .iter_mut()
-                            .map(|sub| match sub {
-                                Value::Object(object) => {
-                                    let (name, value) = object.iter_mut().next().unwrap();
-                                    let value = extract_runtime(value);
-                                    let mut map = Map::new();
-                                    map.insert(name.to_string(), value);
-                                    Value::Object(map)
-                                }
-                                _ => todo!(),
-                            })
-                            .collect();
+                            .map(|sub| extract_named_runtime(sub, "subcommand"))
+                            .collect::<anyhow::Result<Vec<_>>>()?;
                         runtime_map.insert("subcommands".to_string(), Value::Array(runtime_subs));
                     }
-                    _ => {
-                        todo!()
-                    }
+                    _ => anyhow::bail!("'subcommands' must be an array, got {}", subs),
                 }
             }
 
-            Value::Object(runtime_map)
-        }
-        Value::Array(arr) => {
-            Value::Array(arr.iter_mut().map(|item| extract_runtime(item)).collect())
+            Ok(Value::Object(runtime_map))
         }
-        _ => Value::Null,
+        Value::Array(arr) => Ok(Value::Array(
+            arr.iter_mut()
+                .map(extract_runtime)
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )),
+        _ => Ok(Value::Null),
     }
 }
+
+/// Extracts the runtime part of an `{"name": {...}}` entry of the args or
+/// subcommands lists.
+fn extract_named_runtime(entry: &mut Value, kind: &str) -> anyhow::Result<Value> {
+    let Value::Object(object) = entry else {
+        anyhow::bail!(
+            "Every {} must be an object with its name as key, got {}",
+            kind,
+            entry
+        );
+    };
+    let Some((name, value)) = object.iter_mut().next() else {
+        anyhow::bail!("Found an empty {} object without a name", kind);
+    };
+    let value = extract_runtime(value)?;
+    let mut map = Map::new();
+    map.insert(name.to_string(), value);
+    Ok(Value::Object(map))
+}
@@ -0,0 +1,35 @@
+use crate::shell_quote;
+use serde_json::Value;
+
+pub fn generate_nix(name: &str, json_data: &str) -> anyhow::Result<String> {
+    let json: Value = serde_json::from_str(json_data)?;
+    let json_arg = escape_indented_string(&shell_quote(&json.to_string()));
+
+    Ok(format!(
+        r#"{{ writeShellApplication, clap-bash }}:
+
+writeShellApplication {{
+  name = "{name}";
+  runtimeInputs = [ clap-bash ];
+  text = ''
+    exec clap-bash \
+      --add-self-to-env \
+      --json {json_arg} \
+      -- "$@"
+  '';
+}}
+"#,
+        name = escape_string(name),
+    ))
+}
+
+fn escape_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+}
+
+fn escape_indented_string(value: &str) -> String {
+    value.replace("''", "'''").replace("${", "''${")
+}
@@ -0,0 +1,182 @@
+use crate::{shell_quote, ArgValue};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub enum OutputMode {
+    CSource,
+    Positional,
+    ShellFunctions,
+    EnvFile(PathBuf),
+    PrintEnv,
+    AssocArray(String),
+}
+
+pub fn c_source(env: &BTreeMap<String, String>) -> String {
+    let mut source = String::from("#include <stdlib.h>\n\nstatic void clap_bash_setenv(void)\n{\n");
+    for (key, value) in env {
+        source.push_str(&format!(
+            "    setenv(\"{}\", \"{}\", 1);\n",
+            c_escape(key),
+            c_escape(value)
+        ));
+    }
+    source.push_str("}\n");
+    source
+}
+
+pub fn positional(values: &[&str]) -> String {
+    let mut line = String::from("set --");
+    for value in values {
+        line.push(' ');
+        line.push_str(&shell_quote(value));
+    }
+    line.push('\n');
+    line
+}
+
+const SHELL_HELPERS: &str = r#"# Prints the element at the zero based index $3 of $1 split on $2.
+clap_bash_nth() {
+    local rest=$1 delimiter=$2 index=$3 i=0
+    while [[ -n $delimiter && $rest == *"$delimiter"* ]]; do
+        if ((i == index)); then
+            printf '%s\n' "${rest%%"$delimiter"*}"
+            return 0
+        fi
+        rest=${rest#*"$delimiter"}
+        i=$((i + 1))
+    done
+    if ((i == index)); then
+        printf '%s\n' "$rest"
+        return 0
+    fi
+    return 1
+}
+
+# Prints every element of $1 split on $2, one per line.
+clap_bash_split() {
+    local rest=$1 delimiter=$2
+    while [[ -n $delimiter && $rest == *"$delimiter"* ]]; do
+        printf '%s\n' "${rest%%"$delimiter"*}"
+        rest=${rest#*"$delimiter"}
+    done
+    printf '%s\n' "$rest"
+}
+"#;
+
+/// Renders the env as `export` lines followed by bash helpers. Every arg gets
+/// `<VAR>_occurrence N` and `<VAR>_value N M` functions that split its var
+/// with the delimiters configured for it.
+pub fn shell_functions(env: &BTreeMap<String, String>, arg_values: &[ArgValue]) -> String {
+    let mut output = String::new();
+    for (key, value) in env {
+        output.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+    }
+    output.push('\n');
+    output.push_str(SHELL_HELPERS);
+
+    for arg_value in arg_values {
+        let name = &arg_value.env_var.name;
+        let occurrence_delimiter = shell_quote(&arg_value.env_var.occurrence_delimiter);
+        let value_delimiter = shell_quote(&arg_value.env_var.value_delimiter);
+        output.push_str(&format!(
+            "\n{name}_occurrence() {{\n    clap_bash_nth \"${name}\" {occurrence_delimiter} \"$1\"\n}}\n"
+        ));
+        output.push_str(&format!(
+            "\n{name}_value() {{\n    local occurrence\n    occurrence=$({name}_occurrence \"$1\") || return 1\n    clap_bash_nth \"$occurrence\" {value_delimiter} \"$2\"\n}}\n"
+        ));
+    }
+    output
+}
+
+/// Renders the executable and subcommand chain that would run as comments,
+/// followed by sorted `KEY='value'` lines, so the output can be `eval`ed.
+pub fn print_env(
+    env: &BTreeMap<String, String>,
+    executable: &Path,
+    subcommands: &[String],
+) -> String {
+    let mut output = format!("# executable: {:?}\n", executable);
+    output.push_str(&format!("# subcommands: {:?}\n", subcommands.join(" ")));
+    for (key, value) in env {
+        output.push_str(&format!("{}={}\n", key, shell_quote(value)));
+    }
+    output
+}
+
+/// Renders every exported arg as one entry of a bash 4 associative array
+/// keyed by arg id, with the value as it would be exported, so multiple
+/// occurrences are joined with the arg's delimiters.
+pub fn assoc_array(name: &str, arg_values: &[ArgValue]) -> String {
+    let mut output = format!("declare -A {}=(", name);
+    for arg_value in arg_values {
+        output.push_str(&format!(
+            " [{}]={}",
+            shell_quote(&arg_value.arg_name),
+            shell_quote(&arg_value.value)
+        ));
+    }
+    output.push_str(" )\n");
+    output
+}
+
+/// Renders the env as dotenv `KEY=value` lines, sorted by key.
+///
+/// The canonical form always double quotes values, converts CRLF and lone CR
+/// line endings inside values to LF and escapes `\`, `"`, `$` and newlines,
+/// so two runs with the same env produce byte identical files. Otherwise
+/// values are only quoted when they contain more than `[A-Za-z0-9_./:,@+-]`.
+/// Every line, including the last, ends with a single LF either way.
+pub fn dotenv(env: &BTreeMap<String, String>, canonical: bool) -> String {
+    let mut output = String::new();
+    for (key, value) in env {
+        let value = if canonical {
+            double_quote(&value.replace("\r\n", "\n").replace('\r', "\n"))
+        } else if !value.is_empty() && value.chars().all(is_bare_dotenv_char) {
+            value.clone()
+        } else {
+            double_quote(value)
+        };
+        output.push_str(&format!("{}={}\n", key, value));
+    }
+    output
+}
+
+fn is_bare_dotenv_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_./:,@+-".contains(c)
+}
+
+fn double_quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '$' => quoted.push_str("\\$"),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Escapes a string for a C string literal. Non-printable and non-ASCII bytes
+/// use three digit octal escapes, which unlike `\x` can't swallow the next
+/// character, and `?` is escaped to avoid trigraphs.
+fn c_escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'"' => escaped.push_str("\\\""),
+            b'?' => escaped.push_str("\\?"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    escaped
+}
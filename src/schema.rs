@@ -0,0 +1,25 @@
+use anyhow::Context;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+pub fn validate_input(schema_file: &Path, input: &Value) -> anyhow::Result<()> {
+    let schema_data = fs::read_to_string(schema_file)
+        .with_context(|| format!("Failed to read schema file '{}'", schema_file.display()))?;
+    let schema: Value = serde_json::from_str(&schema_data)?;
+    let validator = jsonschema::validator_for(&schema)
+        .map_err(|error| anyhow::anyhow!("Invalid input schema: {}", error))?;
+
+    let errors: Vec<String> = validator
+        .iter_errors(input)
+        .map(|error| format!("  {}: {}", error.instance_path, error))
+        .collect();
+
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "Arguments do not match the input schema:\n{}",
+            errors.join("\n")
+        );
+    }
+    Ok(())
+}
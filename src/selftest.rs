@@ -0,0 +1,155 @@
+use crate::{
+    active_subcommand_path, create_env_vars, dry_run, run, split_config, BoolFormat, Config,
+    RunContext, RunState,
+};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Built-in config covering the runtime/clap split, derived and explicit env
+/// var names, defaults, subcommands and `exec_args` substitution.
+const SAMPLE_CONFIG: &str = r#"{
+    "name": "sample",
+    "args": [
+        {"mode": {"long": "mode", "default_value": "fast"}},
+        {"input": {"required": true, "env_var": "INPUT_FILE"}}
+    ],
+    "subcommands": [
+        {"build": {
+            "executable": "/bin/true",
+            "exec_args": ["--mode", "${MODE}"],
+            "args": [
+                {"jobs": {"long": "jobs", "value_type": "int"}}
+            ]
+        }}
+    ]
+}"#;
+
+const SAMPLE_ARGV: &[&str] = &[
+    "sample", "--mode", "slow", "in.txt", "build", "--jobs", "+04",
+];
+
+/// Runs the sample config through every stage of the pipeline and reports
+/// each one as `ok` or `FAIL`. Fails if any stage did.
+pub fn selftest() -> anyhow::Result<()> {
+    let mut failures = 0;
+    let mut report = |stage: &str, result: anyhow::Result<()>| match result {
+        Ok(()) => println!("ok   {}", stage),
+        Err(error) => {
+            failures += 1;
+            println!("FAIL {}: {:#}", stage, error);
+        }
+    };
+
+    report("extract_runtime", check_split());
+
+    let config = match serde_json::from_str::<Config>(SAMPLE_CONFIG) {
+        Ok(config) => {
+            report("parse", Ok(()));
+            config
+        }
+        Err(error) => {
+            report("parse", Err(error.into()));
+            anyhow::bail!("selftest failed, later stages need the parsed config");
+        }
+    };
+
+    let matches = match config.clap_config.clone().try_get_matches_from(SAMPLE_ARGV) {
+        Ok(matches) => {
+            report("match", Ok(()));
+            matches
+        }
+        Err(error) => {
+            report("match", Err(error.into()));
+            anyhow::bail!("selftest failed, later stages need the matched args");
+        }
+    };
+
+    let mut ctx = RunContext {
+        global_config: &config.global_config,
+        debug: false,
+        subcommand_path: active_subcommand_path(&matches),
+        output: None,
+        canonical: false,
+        detach: false,
+        dry_run: true,
+        no_exec: false,
+        add_resolved_cmd_to_env: false,
+        exec_assertion: None,
+        bool_format: BoolFormat::TrueFalse,
+        level_prefix: false,
+        allow_env_collisions: false,
+    };
+
+    report("create_env_vars", check_env_vars(&ctx, &config, &matches));
+
+    ctx.exec_assertion = Some(dry_run::ExecAssertion {
+        executable: PathBuf::from("/bin/true"),
+        args: Some(vec!["--mode".to_string(), "slow".to_string()]),
+        env: vec!["JOBS=4".to_string()],
+    });
+    report(
+        "run (dry-run)",
+        run(
+            &ctx,
+            &config.clap_config,
+            &matches,
+            &config.command_config,
+            &[],
+            RunState::default(),
+        )
+        .map(|_| ()),
+    );
+
+    if failures > 0 {
+        anyhow::bail!("{} selftest stage(s) failed", failures);
+    }
+    Ok(())
+}
+
+fn check_split() -> anyhow::Result<()> {
+    let mut clap_json = serde_json::from_str(SAMPLE_CONFIG)?;
+    let (_, runtime_json) = split_config(&mut clap_json)?;
+
+    let build_runtime = &runtime_json["subcommands"][0]["build"];
+    if build_runtime["executable"] != "/bin/true" {
+        anyhow::bail!(
+            "executable missing from the runtime config: {}",
+            runtime_json
+        );
+    }
+    if clap_json["subcommands"][0]["build"]
+        .get("executable")
+        .is_some()
+    {
+        anyhow::bail!("executable left in the clap config: {}", clap_json);
+    }
+    if runtime_json["args"][1]["input"]["env_var"] != "INPUT_FILE" {
+        anyhow::bail!("env_var missing from the runtime args: {}", runtime_json);
+    }
+    Ok(())
+}
+
+fn check_env_vars(
+    ctx: &RunContext,
+    config: &Config,
+    matches: &clap::ArgMatches,
+) -> anyhow::Result<()> {
+    let mut state = RunState::default();
+    let env = create_env_vars(
+        ctx,
+        &config.clap_config,
+        matches,
+        &config.command_config,
+        &[],
+        &mut state,
+    )?;
+
+    let expected: BTreeMap<String, String> = [("INPUT_FILE", "in.txt"), ("MODE", "slow")]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    if env != expected {
+        anyhow::bail!("expected {:?}, got {:?}", expected, env);
+    }
+    Ok(())
+}
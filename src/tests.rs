@@ -0,0 +1,927 @@
+use super::*;
+
+fn context(config: &Config) -> RunContext<'_> {
+    RunContext {
+        global_config: &config.global_config,
+        debug: false,
+        subcommand_path: Vec::new(),
+        output: None,
+        canonical: false,
+        detach: false,
+        dry_run: true,
+        no_exec: false,
+        add_resolved_cmd_to_env: false,
+        exec_assertion: None,
+        bool_format: BoolFormat::TrueFalse,
+        level_prefix: false,
+        allow_env_collisions: false,
+    }
+}
+
+/// Env vars the root command of `config` exports for `argv`.
+fn root_env(config: &str, argv: &[&str]) -> anyhow::Result<BTreeMap<String, String>> {
+    Ok(root_state(config, argv)?.0)
+}
+
+/// Env vars of the root command and the state they left behind.
+fn root_state(config: &str, argv: &[&str]) -> anyhow::Result<(BTreeMap<String, String>, RunState)> {
+    let config: Config = serde_json::from_str(config)?;
+    let matches = config.clap_config.clone().try_get_matches_from(argv)?;
+    let mut state = RunState::default();
+    let env = create_env_vars(
+        &context(&config),
+        &config.clap_config,
+        &matches,
+        &config.command_config,
+        &[],
+        &mut state,
+    )?;
+    Ok((env, state))
+}
+
+fn name_limit(max_length: usize, policy: EnvVarNameLimitPolicy) -> EnvVarNameLimit {
+    EnvVarNameLimit { max_length, policy }
+}
+
+#[test]
+fn name_at_the_limit_is_kept() {
+    let limit = name_limit(10, EnvVarNameLimitPolicy::Error);
+    let name = limit_env_var_name("ABCDEFGHIJ".to_string(), &limit).unwrap();
+    assert_eq!(name, "ABCDEFGHIJ");
+}
+
+#[test]
+fn name_over_the_limit_is_an_error() {
+    let limit = name_limit(10, EnvVarNameLimitPolicy::Error);
+    assert!(limit_env_var_name("ABCDEFGHIJK".to_string(), &limit).is_err());
+}
+
+#[test]
+fn name_over_the_limit_is_truncated_deterministically() {
+    let limit = name_limit(10, EnvVarNameLimitPolicy::Truncate);
+    let name = limit_env_var_name("ABCDEFGHIJK".to_string(), &limit).unwrap();
+    assert_eq!(name.len(), 10);
+    assert!(name.starts_with("A_"));
+    assert_eq!(
+        name,
+        limit_env_var_name("ABCDEFGHIJK".to_string(), &limit).unwrap()
+    );
+    assert_ne!(
+        name,
+        limit_env_var_name("ABCDEFGHIJL".to_string(), &limit).unwrap()
+    );
+}
+
+#[test]
+fn non_ascii_names_are_truncated_on_a_char_boundary() {
+    let limit = name_limit(12, EnvVarNameLimitPolicy::Truncate);
+    let name = limit_env_var_name("ABÄÖÜ_LONG_NAME".to_string(), &limit).unwrap();
+    assert!(name.len() <= 12);
+    assert!(name.starts_with("AB_"), "{}", name);
+}
+
+#[test]
+fn limit_too_short_to_truncate_is_an_error() {
+    let limit = name_limit(9, EnvVarNameLimitPolicy::Truncate);
+    assert!(limit_env_var_name("ABCDEFGHIJK".to_string(), &limit).is_err());
+}
+
+#[test]
+fn limit_applies_to_hash_and_index_names() {
+    let env = root_env(
+        r#"{
+            "name": "app",
+            "env_var_name_limit": {"max_length": 12, "policy": "truncate"},
+            "positional_env_vars": "both",
+            "positional_index_prefix": "POSITIONAL_",
+            "args": [{"abcdefghij": {"hash": "sha256"}}]
+        }"#,
+        &["app", "value"],
+    )
+    .unwrap();
+
+    assert_eq!(env["ABCDEFGHIJ"], "value");
+    assert_eq!(env.len(), 3);
+    assert!(env.keys().all(|name| name.len() <= 12), "{:?}", env);
+}
+
+#[test]
+fn flags_are_formatted_from_the_raw_value() {
+    let config = r#"{
+        "name": "app",
+        "args": [{"verbose": {"long": "verbose", "action": "SetTrue", "encode": "base64"}}]
+    }"#;
+    assert_eq!(
+        root_env(config, &["app", "--verbose"]).unwrap()["VERBOSE"],
+        "true"
+    );
+    assert_eq!(root_env(config, &["app"]).unwrap()["VERBOSE"], "false");
+}
+
+const BOOL_FLAGS: &str = r#"{
+    "name": "app",
+    "args": [
+        {"verbose": {"long": "verbose", "action": "SetTrue"}},
+        {"no-cache": {"long": "no-cache", "action": "SetFalse"}},
+        {"color": {
+            "long": "color",
+            "action": "SetTrue",
+            "flag_true": "always",
+            "flag_false": "never"
+        }}
+    ]
+}"#;
+
+/// Env vars of `BOOL_FLAGS` for `argv` with flags exported in `bool_format`.
+fn bool_flag_env(bool_format: BoolFormat, argv: &[&str]) -> BTreeMap<String, String> {
+    let config: Config = serde_json::from_str(BOOL_FLAGS).unwrap();
+    let mut argv_with_name = vec!["app"];
+    argv_with_name.extend_from_slice(argv);
+    let matches = config
+        .clap_config
+        .clone()
+        .try_get_matches_from(argv_with_name)
+        .unwrap();
+    let ctx = RunContext {
+        bool_format,
+        ..context(&config)
+    };
+    create_env_vars(
+        &ctx,
+        &config.clap_config,
+        &matches,
+        &config.command_config,
+        &[],
+        &mut RunState::default(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn passed_flags_follow_the_bool_format() {
+    let argv = ["--verbose", "--no-cache"];
+    for (bool_format, truthy, falsy) in [
+        (BoolFormat::TrueFalse, "true", "false"),
+        (BoolFormat::OneZero, "1", "0"),
+        (BoolFormat::YesNo, "yes", "no"),
+    ] {
+        let env = bool_flag_env(bool_format, &argv);
+        assert_eq!(env["VERBOSE"], truthy);
+        assert_eq!(env["NO_CACHE"], falsy);
+    }
+}
+
+#[test]
+fn unset_flags_follow_the_bool_format() {
+    for (bool_format, truthy, falsy) in [
+        (BoolFormat::TrueFalse, "true", "false"),
+        (BoolFormat::OneZero, "1", "0"),
+        (BoolFormat::YesNo, "yes", "no"),
+    ] {
+        let env = bool_flag_env(bool_format, &[]);
+        assert_eq!(env["VERBOSE"], falsy);
+        assert_eq!(env["NO_CACHE"], truthy);
+    }
+}
+
+#[test]
+fn flag_true_and_flag_false_override_the_bool_format() {
+    for bool_format in [
+        BoolFormat::TrueFalse,
+        BoolFormat::OneZero,
+        BoolFormat::YesNo,
+    ] {
+        assert_eq!(bool_flag_env(bool_format, &["--color"])["COLOR"], "always");
+        assert_eq!(bool_flag_env(bool_format, &[])["COLOR"], "never");
+    }
+}
+
+#[test]
+fn unknown_argv_group_members_are_rejected() {
+    let error = serde_json::from_str::<Config>(
+        r#"{
+            "name": "app",
+            "args": [{"verbose": {"long": "verbose", "action": "SetTrue"}}],
+            "argv_groups": [{"env_var": "FLAGS", "args": ["verbose", "verbsoe"]}]
+        }"#,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("'verbsoe'"), "{}", error);
+}
+
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            decoded.push(u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+#[test]
+fn url_encoding_round_trips() {
+    for value in [
+        "plain",
+        "a b&c=d/e?f#g",
+        "100% ~-._",
+        "ümlaut",
+        ":/?#[]@!$&'()*+,;=",
+    ] {
+        assert_eq!(url_decode(&url_encode(value)), value);
+    }
+}
+
+#[test]
+fn url_encoding_encodes_every_reserved_character() {
+    assert_eq!(
+        url_encode(":/?#[]@!$&'()*+,;="),
+        "%3A%2F%3F%23%5B%5D%40%21%24%26%27%28%29%2A%2B%2C%3B%3D"
+    );
+    assert_eq!(url_encode("AZaz09-._~"), "AZaz09-._~");
+}
+
+#[test]
+fn url_encoded_arg_is_exported_encoded() {
+    let config = r#"{
+        "name": "app",
+        "args": [{"query": {"long": "query", "encode": "url"}}]
+    }"#;
+    let env = root_env(config, &["app", "--query", "a b&c"]).unwrap();
+    assert_eq!(env["QUERY"], "a%20b%26c");
+    assert_eq!(url_decode(&env["QUERY"]), "a b&c");
+}
+
+#[test]
+fn only_if_overridden_skips_the_default() {
+    let config = r#"{
+        "name": "app",
+        "args": [{"mode": {"long": "mode", "default_value": "fast", "only_if_overridden": true}}]
+    }"#;
+    let env = root_env(config, &["app"]).unwrap();
+    assert!(!env.contains_key("MODE"), "{:?}", env);
+}
+
+#[test]
+fn only_if_overridden_exports_a_passed_value_equal_to_the_default() {
+    let config = r#"{
+        "name": "app",
+        "args": [{"mode": {"long": "mode", "default_value": "fast", "only_if_overridden": true}}]
+    }"#;
+    let env = root_env(config, &["app", "--mode", "fast"]).unwrap();
+    assert_eq!(env["MODE"], "fast");
+}
+
+#[test]
+fn bool_spellings_are_normalized() {
+    for value in ["true", "TRUE", "1", "yes", "On"] {
+        assert_eq!(ValueType::Bool.normalize("flag", value).unwrap(), "true");
+    }
+    for value in ["false", "False", "0", "no", "OFF"] {
+        assert_eq!(ValueType::Bool.normalize("flag", value).unwrap(), "false");
+    }
+}
+
+#[test]
+fn ints_lose_signs_and_leading_zeros() {
+    assert_eq!(ValueType::Int.normalize("jobs", "+007").unwrap(), "7");
+    assert_eq!(ValueType::Int.normalize("jobs", "-012").unwrap(), "-12");
+    assert_eq!(ValueType::Int.normalize("jobs", "0").unwrap(), "0");
+}
+
+#[test]
+fn floats_use_the_shortest_round_trip_form() {
+    assert_eq!(ValueType::Float.normalize("ratio", "1.50").unwrap(), "1.5");
+    assert_eq!(ValueType::Float.normalize("ratio", "+02.0").unwrap(), "2");
+    assert_eq!(ValueType::Float.normalize("ratio", "1e3").unwrap(), "1000");
+    assert_eq!(ValueType::Float.normalize("ratio", "0.1").unwrap(), "0.1");
+}
+
+#[test]
+fn mismatched_values_are_rejected() {
+    for (value_type, value) in [
+        (ValueType::Bool, "maybe"),
+        (ValueType::Int, "1.5"),
+        (ValueType::Int, "twelve"),
+        (ValueType::Float, "1,5"),
+        (ValueType::Float, ""),
+    ] {
+        let error = value_type.normalize("arg", value).unwrap_err();
+        assert!(error.to_string().contains("of arg 'arg'"), "{}", error);
+    }
+}
+
+#[test]
+fn explicit_names_are_literal_and_derived_names_follow_the_policy() {
+    let config = r#"{
+        "name": "app",
+        "env_prefix": "APP_",
+        "args": [
+            {"http-proxy": {"long": "http-proxy", "env_var": "http_proxy"}},
+            {"no-proxy": {"long": "no-proxy"}}
+        ]
+    }"#;
+    let env = root_env(config, &["app", "--http-proxy", "a", "--no-proxy", "b"]).unwrap();
+    assert_eq!(env["http_proxy"], "a");
+    assert_eq!(env["APP_NO_PROXY"], "b");
+    assert_eq!(env.len(), 2);
+}
+
+#[test]
+fn explicit_names_are_exempt_from_the_name_limit() {
+    let config = r#"{
+        "name": "app",
+        "env_var_name_limit": {"max_length": 8},
+        "args": [
+            {"proxy": {"long": "proxy", "env_var": "http_proxy_url"}},
+            {"no-proxy-list": {"long": "no-proxy-list"}}
+        ]
+    }"#;
+    let env = root_env(config, &["app", "--proxy", "a"]).unwrap();
+    assert_eq!(env["http_proxy_url"], "a");
+    assert!(root_env(config, &["app", "--no-proxy-list", "b"]).is_err());
+}
+
+#[test]
+fn explicit_names_must_be_identifiers() {
+    let env = root_env(
+        r#"{
+            "name": "app",
+            "args": [{"proxy": {"long": "proxy", "env_var": "http-proxy"}}]
+        }"#,
+        &["app", "--proxy", "a"],
+    );
+    assert!(env.is_err());
+}
+
+/// Writes `contents` to a file in the temp dir unique to this test process.
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("clap-bash-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn read_from_file_env(file: &Path, keep_trailing_newline: bool) -> String {
+    let config = format!(
+        r#"{{
+            "name": "app",
+            "args": [{{"token": {{
+                "long": "token",
+                "read_from_file": true,
+                "keep_trailing_newline": {}
+            }}}}]
+        }}"#,
+        keep_trailing_newline
+    );
+    let file = file.to_str().unwrap();
+    root_env(&config, &["app", "--token", file]).unwrap()["TOKEN"].clone()
+}
+
+#[test]
+fn read_from_file_strips_trailing_newlines_by_default() {
+    let with_newline = temp_file("strip-with-newline", "secret\n");
+    let without_newline = temp_file("strip-without-newline", "secret");
+    assert_eq!(read_from_file_env(&with_newline, false), "secret");
+    assert_eq!(read_from_file_env(&without_newline, false), "secret");
+}
+
+#[test]
+fn read_from_file_keeps_trailing_newlines_when_asked() {
+    let with_newline = temp_file("keep-with-newline", "secret\n");
+    let without_newline = temp_file("keep-without-newline", "secret");
+    assert_eq!(read_from_file_env(&with_newline, true), "secret\n");
+    assert_eq!(read_from_file_env(&without_newline, true), "secret");
+}
+
+const MIXED_POSITIONALS: &str = r#"{
+    "name": "app",
+    "positional_env_vars": "$MODE",
+    "args": [
+        {"input": {"help": "First positional"}},
+        {"verbose": {"long": "verbose", "action": "SetTrue"}},
+        {"output": {"help": "Second positional"}},
+        {"level": {"long": "level"}}
+    ]
+}"#;
+
+fn mixed_positionals_env(mode: &str) -> BTreeMap<String, String> {
+    let config = MIXED_POSITIONALS.replace("$MODE", mode);
+    root_env(
+        &config,
+        &["app", "--level", "3", "in.txt", "--verbose", "out.txt"],
+    )
+    .unwrap()
+}
+
+#[test]
+fn positionals_are_keyed_by_index_between_options() {
+    let env = mixed_positionals_env("index");
+    assert_eq!(env["POS_1"], "in.txt");
+    assert_eq!(env["POS_2"], "out.txt");
+    assert_eq!(env["LEVEL"], "3");
+    assert_eq!(env["VERBOSE"], "true");
+    assert!(!env.contains_key("INPUT"));
+    assert!(!env.contains_key("OUTPUT"));
+}
+
+#[test]
+fn positionals_are_keyed_by_name_and_index() {
+    let env = mixed_positionals_env("both");
+    assert_eq!(env["INPUT"], "in.txt");
+    assert_eq!(env["POS_1"], "in.txt");
+    assert_eq!(env["OUTPUT"], "out.txt");
+    assert_eq!(env["POS_2"], "out.txt");
+    assert!(!env.contains_key("POS_3"));
+}
+
+fn env_of(names: &[&str]) -> BTreeMap<String, String> {
+    names
+        .iter()
+        .map(|name| (name.to_string(), String::new()))
+        .collect()
+}
+
+#[test]
+fn names_differing_only_in_case_collide() {
+    let env = env_of(&["Path", "PATH", "HOME"]);
+    assert_eq!(case_insensitive_collisions(&env), vec![("PATH", "Path")]);
+}
+
+#[test]
+fn distinct_names_do_not_collide() {
+    let env = env_of(&["PATH", "PATH_1", "HOME", "_PATH"]);
+    assert!(case_insensitive_collisions(&env).is_empty());
+}
+
+#[test]
+fn every_spelling_of_a_name_is_reported() {
+    let env = env_of(&["path", "Path", "PATH"]);
+    assert_eq!(case_insensitive_collisions(&env).len(), 2);
+}
+
+/// Env vars the root command exports for a TOML `config`, run through the
+/// same JSON conversion as `--toml`.
+fn toml_root_env(config: &str, argv: &[&str]) -> anyhow::Result<BTreeMap<String, String>> {
+    root_env(&ConfigFormat::Toml.to_json(config)?, argv)
+}
+
+const PAIR_ARGV: &[&str] = &["app", "--pair", "a", "b", "--pair", "c", "d"];
+
+#[test]
+fn toml_simple_env_var_uses_the_default_delimiters() {
+    let env = toml_root_env(
+        r#"
+        name = "app"
+
+        [[args]]
+        pair = { long = "pair", num_args = 2, action = "Append", env_var = "PAIRS" }
+        "#,
+        PAIR_ARGV,
+    )
+    .unwrap();
+    assert_eq!(env["PAIRS"], "a;b,c;d");
+    assert!(!env.contains_key("PAIR"));
+}
+
+#[test]
+fn toml_full_env_var_keeps_its_delimiters() {
+    let env = toml_root_env(
+        r#"
+        name = "app"
+
+        [[args]]
+        [args.pair]
+        long = "pair"
+        num_args = 2
+        action = "Append"
+        env_var = { name = "PAIRS", value_delimiter = ":", occurrence_delimiter = " " }
+        "#,
+        PAIR_ARGV,
+    )
+    .unwrap();
+    assert_eq!(env["PAIRS"], "a:b c:d");
+}
+
+#[test]
+fn toml_full_env_var_falls_back_to_the_default_delimiters() {
+    let env = toml_root_env(
+        r#"
+        name = "app"
+
+        [[args]]
+        [args.pair]
+        long = "pair"
+        num_args = 2
+        action = "Append"
+
+        [args.pair.env_var]
+        name = "PAIRS"
+        occurrence_delimiter = "|"
+        "#,
+        PAIR_ARGV,
+    )
+    .unwrap();
+    assert_eq!(env["PAIRS"], "a;b|c;d");
+}
+
+fn ranged_env(argv: &[&str]) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut argv_with_name = vec!["app"];
+    argv_with_name.extend_from_slice(argv);
+    root_env(
+        r#"{
+            "name": "app",
+            "args": [{"level": {
+                "long": "level",
+                "action": "Append",
+                "min": 1,
+                "max": 10
+            }}]
+        }"#,
+        &argv_with_name,
+    )
+}
+
+#[test]
+fn range_bounds_are_inclusive() {
+    assert_eq!(ranged_env(&["--level", "1"]).unwrap()["LEVEL"], "1");
+    assert_eq!(ranged_env(&["--level", "10"]).unwrap()["LEVEL"], "10");
+    assert_eq!(ranged_env(&["--level", "2.5"]).unwrap()["LEVEL"], "2.5");
+}
+
+#[test]
+fn values_outside_the_range_are_rejected() {
+    assert!(ranged_env(&["--level", "0.99"]).is_err());
+    assert!(ranged_env(&["--level", "10.01"]).is_err());
+    assert!(ranged_env(&["--level=-1"]).is_err());
+}
+
+#[test]
+fn non_numeric_values_are_rejected() {
+    let error = ranged_env(&["--level", "high"]).unwrap_err();
+    assert!(format!("{:#}", error).contains("not a number"));
+}
+
+#[test]
+fn every_occurrence_is_range_checked() {
+    assert!(ranged_env(&["--level", "3", "--level", "5"]).is_ok());
+    assert!(ranged_env(&["--level", "3", "--level", "11"]).is_err());
+}
+
+#[test]
+fn range_message_replaces_the_generic_error() {
+    let error = root_env(
+        r#"{
+            "name": "app",
+            "args": [{"port": {
+                "long": "port",
+                "min": 1024,
+                "range_message": "Port {value} is privileged"
+            }}]
+        }"#,
+        &["app", "--port", "80"],
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", error).contains("Port 80 is privileged"));
+}
+
+fn format_name(env_var_format: &str) -> anyhow::Result<String> {
+    let command = Command::new("app")
+        .arg(Arg::new("dry-run").long("dry-run"))
+        .arg(Arg::new("input"));
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_id() == "dry-run")
+        .unwrap();
+    format_env_var_name(env_var_format, &command, arg)
+}
+
+#[test]
+fn env_var_format_expands_placeholders() {
+    assert_eq!(format_name("OPT_{ID}").unwrap(), "OPT_DRY_RUN");
+    assert_eq!(format_name("{kind}_{ID}").unwrap(), "option_DRY_RUN");
+    assert_eq!(format_name("X{index}_{ID}").unwrap(), "X_DRY_RUN");
+}
+
+#[test]
+fn env_var_format_rejects_unknown_placeholders() {
+    let error = format_name("OPT_{NAME}").unwrap_err();
+    assert!(error.to_string().contains("Unknown placeholder '{NAME}'"));
+}
+
+#[test]
+fn env_var_format_rejects_unbalanced_braces() {
+    let error = format_name("OPT_{ID").unwrap_err();
+    assert!(error.to_string().contains("Unclosed '{'"));
+    let error = format_name("OPT_ID}").unwrap_err();
+    assert!(error.to_string().contains("Unmatched '}'"));
+}
+
+#[test]
+fn env_var_format_rejects_results_that_are_not_identifiers() {
+    assert!(format_name("{id}").is_err());
+    assert!(format_name("1_{ID}").is_err());
+    assert!(format_name("{{{ID}}}").is_err());
+    assert!(format_name("").is_err());
+}
+
+/// The args clap-bash hands to the wrapped command for its own `argv`.
+fn trailing_args(argv: &[&str]) -> Vec<String> {
+    let mut cli_argv = vec!["clap-bash", "--json", "{}"];
+    cli_argv.extend_from_slice(argv);
+    Cli::try_parse_from(cli_argv)
+        .unwrap()
+        .trailing
+        .into_iter()
+        .map(|arg| arg.into_string().unwrap())
+        .collect()
+}
+
+/// Env vars of the root command for the args following clap-bash's `--`.
+fn wrapped_env(config: &str, argv: &[&str]) -> anyhow::Result<BTreeMap<String, String>> {
+    let trailing = trailing_args(argv);
+    let mut wrapped_argv = vec!["app"];
+    wrapped_argv.extend(trailing.iter().map(String::as_str));
+    root_env(config, &wrapped_argv)
+}
+
+const LAST_POSITIONAL: &str = r#"{
+    "name": "app",
+    "args": [
+        {"flag": {"long": "flag", "action": "SetTrue"}},
+        {"rest": {"last": true, "num_args": "1.."}}
+    ]
+}"#;
+
+const TRAILING_VAR_ARG: &str = r#"{
+    "name": "app",
+    "args": [
+        {"verbose": {"long": "verbose", "action": "SetTrue"}},
+        {"cmd": {"num_args": "1..", "trailing_var_arg": true, "allow_hyphen_values": true}}
+    ]
+}"#;
+
+#[test]
+fn only_the_first_separator_is_consumed() {
+    assert_eq!(
+        trailing_args(&["--", "--flag", "--", "last"]),
+        ["--flag", "--", "last"]
+    );
+    assert_eq!(trailing_args(&["--", "--", "--"]), ["--", "--"]);
+}
+
+#[test]
+fn wrapped_last_positional_needs_a_second_separator() {
+    let env = wrapped_env(LAST_POSITIONAL, &["--", "--flag", "--", "--not-a-flag"]).unwrap();
+    assert_eq!(env["FLAG"], "true");
+    assert_eq!(env["REST"], "--not-a-flag");
+
+    assert!(wrapped_env(LAST_POSITIONAL, &["--", "--flag", "value"]).is_err());
+}
+
+#[test]
+fn wrapped_trailing_var_arg_needs_no_separator() {
+    let env = wrapped_env(
+        TRAILING_VAR_ARG,
+        &["--", "--verbose", "run", "--flag", "-x"],
+    )
+    .unwrap();
+    assert_eq!(env["VERBOSE"], "true");
+    assert_eq!(env["CMD"], "run;--flag;-x");
+}
+
+#[test]
+fn wrapped_trailing_var_arg_keeps_a_nested_separator() {
+    let env = wrapped_env(TRAILING_VAR_ARG, &["--", "run", "--", "--verbose"]).unwrap();
+    assert_eq!(env["VERBOSE"], "false");
+    assert_eq!(env["CMD"], "run;--;--verbose");
+}
+
+const FORWARDED_FLAGS: &str = r#"{
+    "name": "app",
+    "args": [
+        {"color": {"long": "color", "action": "SetTrue", "forward": true}},
+        {"no-cache": {"long": "no-cache", "action": "SetFalse", "forward": true}},
+        {"verbose": {"short": "v", "action": "Count", "forward": true}}
+    ]
+}"#;
+
+fn forwarded_args(argv: &[&str]) -> Vec<String> {
+    let mut argv_with_name = vec!["app"];
+    argv_with_name.extend_from_slice(argv);
+    root_state(FORWARDED_FLAGS, &argv_with_name)
+        .unwrap()
+        .1
+        .forwarded_args
+}
+
+#[test]
+fn set_true_flags_are_forwarded_only_when_passed() {
+    assert_eq!(forwarded_args(&["--color"]), ["--color"]);
+    assert!(forwarded_args(&[]).is_empty());
+}
+
+#[test]
+fn set_false_flags_are_forwarded_only_when_passed() {
+    assert_eq!(forwarded_args(&["--no-cache"]), ["--no-cache"]);
+    assert!(forwarded_args(&[]).is_empty());
+}
+
+#[test]
+fn count_flags_are_forwarded_per_count() {
+    assert_eq!(forwarded_args(&["-vvv"]), ["-v", "-v", "-v"]);
+    assert!(forwarded_args(&[]).is_empty());
+}
+
+fn path_list_config(arg: &str) -> Config {
+    serde_json::from_str(&format!(
+        r#"{{"name": "app", "args": [{{"paths": {}}}]}}"#,
+        arg
+    ))
+    .unwrap()
+}
+
+#[test]
+fn single_value_path_lists_keep_the_default_delimiters() {
+    let config = path_list_config(r#"{"long": "paths", "path_list": true}"#);
+    assert!(check_delimiters(&config).is_ok());
+}
+
+#[test]
+fn path_list_separator_conflicts_with_used_delimiters() {
+    let config = path_list_config(&format!(
+        r#"{{"long": "paths", "path_list": true, "action": "Append",
+            "env_var": {{"name": "PATHS", "occurrence_delimiter": "{}"}}}}"#,
+        PATH_LIST_SEPARATOR
+    ));
+    assert!(check_delimiters(&config).is_err());
+
+    let config = path_list_config(&format!(
+        r#"{{"long": "paths", "path_list": true, "num_args": 2,
+            "env_var": {{"name": "PATHS", "value_delimiter": "{}", "occurrence_delimiter": "|"}}}}"#,
+        PATH_LIST_SEPARATOR
+    ));
+    assert!(check_delimiters(&config).is_err());
+}
+
+const PROVIDED: &str = r#"{
+    "name": "app",
+    "env_prefix": "APP_",
+    "provided_env_var": "PROVIDED",
+    "args": [{"verbose": {"long": "verbose", "action": "SetTrue"}}]
+}"#;
+
+#[test]
+fn provided_env_var_gets_the_env_prefix() {
+    let env = root_env(PROVIDED, &["app", "--verbose"]).unwrap();
+    assert_eq!(env["APP_PROVIDED"], "verbose");
+    assert!(!env.contains_key("PROVIDED"));
+
+    let config: Config = serde_json::from_str(PROVIDED).unwrap();
+    let listed = list::list_env_vars(&config).unwrap();
+    assert!(listed
+        .lines()
+        .any(|line| line.starts_with("APP_PROVIDED\t")));
+}
+
+const COUNTED: &str = r#"{
+    "name": "app",
+    "args": [
+        {"verbose": {"short": "v", "long": "verbose", "action": "Count"}},
+        {"quiet": {"long": "quiet", "action": "SetTrue"}},
+        {"no-color": {"long": "no-color", "action": "SetFalse"}}
+    ]
+}"#;
+
+#[test]
+fn count_flags_export_the_count() {
+    let env = root_env(COUNTED, &["app", "--verbose", "--verbose"]).unwrap();
+    assert_eq!(env["VERBOSE"], "2");
+    let env = root_env(COUNTED, &["app", "-vvv"]).unwrap();
+    assert_eq!(env["VERBOSE"], "3");
+    let env = root_env(COUNTED, &["app"]).unwrap();
+    assert_eq!(env["VERBOSE"], "0");
+}
+
+#[test]
+fn unset_bool_flags_are_exported() {
+    let env = root_env(COUNTED, &["app"]).unwrap();
+    assert_eq!(env["QUIET"], "false");
+    assert_eq!(env["NO_COLOR"], "true");
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+#[test]
+fn integer_sums_stay_integers() {
+    let sum = Reducer::Sum.reduce("n", &strings(&["1", "-4", "10"]));
+    assert_eq!(sum.unwrap(), "7");
+}
+
+#[test]
+fn overflowing_integer_sums_are_an_error() {
+    let max = i128::MAX.to_string();
+    let error = Reducer::Sum
+        .reduce("n", &strings(&[&max, "1"]))
+        .unwrap_err();
+    assert!(error.to_string().contains("overflows"), "{}", error);
+}
+
+fn listed_names(config: &str) -> Vec<String> {
+    let config: Config = serde_json::from_str(config).unwrap();
+    list::list_env_vars(&config)
+        .unwrap()
+        .lines()
+        .map(|line| line.split('\t').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn listed_positional_names_follow_positional_env_vars() {
+    let names = listed_names(&MIXED_POSITIONALS.replace("$MODE", "index"));
+    assert_eq!(names, ["POS_1", "VERBOSE", "POS_2", "LEVEL"]);
+
+    let names = listed_names(&MIXED_POSITIONALS.replace("$MODE", "both"));
+    assert_eq!(
+        names,
+        ["INPUT", "POS_1", "VERBOSE", "OUTPUT", "POS_2", "LEVEL"]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_values_are_restored_byte_identical() {
+    use std::os::unix::ffi::OsStringExt;
+
+    let config: Config =
+        serde_json::from_str(r#"{"name": "app", "args": [{"input": {"help": "Input path"}}]}"#)
+            .unwrap();
+    let input = OsString::from_vec(b"caf\xe9.txt".to_vec());
+    let matches = config
+        .clap_config
+        .clone()
+        .try_get_matches_from([OsString::from("app"), input.clone()])
+        .unwrap();
+    let mut state = RunState::default();
+    let env = create_env_vars(
+        &context(&config),
+        &config.clap_config,
+        &matches,
+        &config.command_config,
+        &[],
+        &mut state,
+    )
+    .unwrap();
+    assert_eq!(env["INPUT"], input.to_string_lossy());
+
+    let restored = restore_raw_env(&env, &state);
+    assert_eq!(restored["INPUT"].clone().into_vec(), input.into_vec());
+}
+
+#[test]
+fn validate_all_reports_setup_problems_with_the_rest() {
+    let json = serde_json::json!({
+        "name": "app",
+        "env_prefix": "1APP_",
+        "args": [
+            {"verbose": {"long": "verbose", "action": "SetTrue", "value_name": "V"}}
+        ],
+        "argv_groups": [{"env_var": "FLAGS", "args": ["verbsoe"]}],
+        "static_env": {"not-an-identifier": "x"}
+    });
+    let (config, problems) = Config::from_json(json).unwrap();
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+
+    let error = validate::validate(&config, problems, false).unwrap_err();
+    let report = error.to_string();
+    for expected in [
+        "'value_name' has no effect on a flag",
+        "env_prefix '1APP_'",
+        "member 'verbsoe' has no matching clap argument",
+        "'not-an-identifier' is not a valid identifier",
+    ] {
+        assert!(report.contains(expected), "{}", report);
+    }
+}
+
+#[test]
+fn validate_fail_fast_stops_at_the_first_setup_problem() {
+    let json = serde_json::json!({
+        "name": "app",
+        "env_prefix": "1APP_",
+        "args": [{"verbose": {"long": "verbose", "action": "SetTrue", "value_name": "V"}}]
+    });
+    let (config, problems) = Config::from_json(json).unwrap();
+    let error = validate::validate(&config, problems, true).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("'value_name' has no effect on a flag"));
+    assert!(!error.to_string().contains("env_prefix"));
+}
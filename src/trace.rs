@@ -0,0 +1,113 @@
+use crate::{split_config, ArgConfig, ArgValue};
+use clap::ArgMatches;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Structured record of how a config resolved, printed by `--trace-json`.
+/// Every stage is a named member of the top level object, so traces of
+/// different runs can be diffed stage by stage.
+#[derive(Default)]
+pub struct Trace {
+    stages: Map<String, Value>,
+    args: Vec<Value>,
+}
+
+impl Trace {
+    pub fn input(&mut self, source: &str, path: Option<&str>) {
+        let mut input = Map::new();
+        input.insert("source".to_string(), Value::from(source));
+        input.insert("path".to_string(), path.map_or(Value::Null, Value::from));
+        self.stages
+            .insert("input".to_string(), Value::Object(input));
+    }
+
+    /// Records the runtime/clap/global split by running it again on the raw
+    /// config, since deserializing `Config` doesn't keep the intermediate JSON.
+    pub fn split(&mut self, json_data: &str) -> anyhow::Result<()> {
+        let mut clap_json: Value = serde_json::from_str(json_data)?;
+        let (global_json, runtime_json) = split_config(&mut clap_json)?;
+
+        let mut split = Map::new();
+        split.insert("clap".to_string(), clap_json);
+        split.insert("runtime".to_string(), runtime_json);
+        split.insert("global".to_string(), global_json);
+        self.stages
+            .insert("split".to_string(), Value::Object(split));
+        Ok(())
+    }
+
+    pub fn subcommand_path(&mut self, path: &[String]) {
+        self.stages
+            .insert("subcommand_path".to_string(), Value::from(path.to_vec()));
+    }
+
+    /// Records one exported arg. `args` is `None` for values that didn't come
+    /// from the matches, like `default_template` ones.
+    pub fn arg(
+        &mut self,
+        args: Option<&ArgMatches>,
+        arg_config: &ArgConfig,
+        arg_value: &ArgValue,
+        depth: usize,
+    ) {
+        let value_source = match args.and_then(|args| args.value_source(&arg_value.arg_name)) {
+            Some(source) => format!("{:?}", source),
+            None => "default_template".to_string(),
+        };
+        let raw = args
+            .and_then(|args| args.get_raw_occurrences(&arg_value.arg_name))
+            .map(|occurrences| {
+                occurrences
+                    .map(|occurrence| {
+                        occurrence
+                            .map(|value| Value::from(value.to_string_lossy().into_owned()))
+                            .collect::<Vec<_>>()
+                    })
+                    .map(Value::Array)
+                    .collect::<Vec<_>>()
+            })
+            .map_or(Value::Null, Value::Array);
+
+        let mut arg = Map::new();
+        arg.insert("arg".to_string(), Value::from(arg_value.arg_name.clone()));
+        arg.insert("depth".to_string(), Value::from(depth));
+        arg.insert("value_source".to_string(), Value::from(value_source));
+        arg.insert("raw".to_string(), raw);
+        arg.insert(
+            "transforms".to_string(),
+            Value::from(arg_config.transform_names()),
+        );
+        arg.insert(
+            "env_var".to_string(),
+            Value::from(arg_value.env_var.name.clone()),
+        );
+        arg.insert("value".to_string(), Value::from(arg_value.value.clone()));
+        self.args.push(Value::Object(arg));
+    }
+
+    pub fn env(&mut self, env: &BTreeMap<String, String>, file_env: &BTreeMap<String, String>) {
+        let to_json = |env: &BTreeMap<String, String>| {
+            Value::Object(
+                env.iter()
+                    .map(|(key, value)| (key.clone(), Value::from(value.clone())))
+                    .collect(),
+            )
+        };
+
+        let mut stage = Map::new();
+        stage.insert("generated".to_string(), to_json(env));
+        stage.insert("file".to_string(), to_json(file_env));
+        self.stages.insert("env".to_string(), Value::Object(stage));
+    }
+
+    /// Prints the trace to stderr, keeping stdout free for the output modes.
+    pub fn emit(mut self) -> anyhow::Result<()> {
+        self.stages
+            .insert("args".to_string(), Value::Array(self.args));
+        eprintln!(
+            "{}",
+            serde_json::to_string_pretty(&Value::Object(self.stages))?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{
+    arg_env_var_names, delimiter_conflicts, final_env_var_name, get_subcommand_config,
+    is_posix_identifier, CommandConfig, Config, GlobalConfig,
+};
+use clap::Command;
+
+struct Validator {
+    fail_fast: bool,
+    problems: Vec<String>,
+}
+
+impl Validator {
+    fn report(&mut self, path: &str, problem: String) -> anyhow::Result<()> {
+        self.report_problem(format!("{}: {}", path, problem))
+    }
+
+    /// Reports a problem that already names where it belongs.
+    fn report_problem(&mut self, problem: String) -> anyhow::Result<()> {
+        if self.fail_fast {
+            anyhow::bail!("{}", problem);
+        }
+        self.problems.push(problem);
+        Ok(())
+    }
+}
+
+/// Checks every command and arg of the config. `setup_problems` are the ones
+/// found while building the config, see `Config::from_json`, and come
+/// first. In fail-fast mode the first problem is returned as error,
+/// otherwise all problems are collected into one error, each prefixed with
+/// the command path and arg it belongs to.
+pub fn validate(
+    config: &Config,
+    setup_problems: Vec<String>,
+    fail_fast: bool,
+) -> anyhow::Result<String> {
+    let mut validator = Validator {
+        fail_fast,
+        problems: Vec::new(),
+    };
+    for problem in setup_problems {
+        validator.report_problem(problem)?;
+    }
+    validate_command(
+        &mut validator,
+        &config.clap_config,
+        &config.command_config,
+        &config.global_config,
+        config.clap_config.get_name(),
+    )?;
+    let conflicts = delimiter_conflicts(
+        &config.global_config,
+        &config.clap_config,
+        &config.command_config,
+    );
+    for (path, conflict) in conflicts {
+        validator.report(&path, format!("conflicting delimiters, {}", conflict))?;
+    }
+
+    if !validator.problems.is_empty() {
+        anyhow::bail!(
+            "Found {} problem(s) in the config:\n  {}",
+            validator.problems.len(),
+            validator.problems.join("\n  ")
+        );
+    }
+    Ok("Config is valid\n".to_string())
+}
+
+fn validate_command(
+    validator: &mut Validator,
+    command: &Command,
+    config: &CommandConfig,
+    global_config: &GlobalConfig,
+    path: &str,
+) -> anyhow::Result<()> {
+    for arg in command.get_arguments() {
+        let arg_name = arg.get_id().as_str();
+        let Some(arg_config) = config.args.iter().find_map(|args| args.get(arg_name)) else {
+            validator.report(path, format!("no config found for argument '{}'", arg_name))?;
+            continue;
+        };
+
+        if let Err(error) = arg_env_var_names(global_config, "", command, arg, arg_config) {
+            validator.report(path, format!("argument '{}': {}", arg_name, error))?;
+        }
+    }
+
+    // Unknown argv group members are part of the setup problems.
+    for group in &config.argv_groups {
+        if let Err(error) = final_env_var_name(global_config, "", &group.env_var, false) {
+            validator.report(path, format!("argv group {}: {}", group.env_var, error))?;
+        }
+    }
+
+    let explicit_names = config
+        .argv_groups
+        .iter()
+        .map(|group| &group.env_var)
+        .chain(config.static_env.keys());
+    for name in explicit_names {
+        if !is_posix_identifier(name) {
+            validator.report(
+                path,
+                format!("env var name '{}' is not a valid identifier", name),
+            )?;
+        }
+    }
+
+    let is_leaf = command.get_subcommands().next().is_none();
+    let has_executable = config.executable.is_some()
+        || config.executable_resolver.is_some()
+        || config.external_passthrough;
+    if is_leaf && !has_executable {
+        validator.report(path, "no executable configured".to_string())?;
+    }
+
+    for subcommand in command.get_subcommands() {
+        let name = subcommand.get_name();
+        let subpath = format!("{} {}", path, name);
+        match get_subcommand_config(config, name) {
+            Some(subconfig) => {
+                validate_command(validator, subcommand, subconfig, global_config, &subpath)?
+            }
+            None => validator.report(&subpath, "no config found for subcommand".to_string())?,
+        }
+    }
+    Ok(())
+}
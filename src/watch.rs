@@ -0,0 +1,57 @@
+use crate::{config_file, is_stdin, process_config, read_config_data, Cli};
+use notify::{Event, RecursiveMode, Watcher};
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Re-runs the pipeline whenever the config file changes. The parent
+/// directory is watched instead of the file itself, so editors that save by
+/// atomically replacing the file keep triggering updates.
+pub fn watch(cli: &Cli) -> anyhow::Result<()> {
+    let Some((_, config_file)) = config_file(cli) else {
+        anyhow::bail!("--watch requires a config file")
+    };
+    if is_stdin(config_file) {
+        anyhow::bail!("--watch can't watch a config read from stdin")
+    }
+    let config_file = fs::canonicalize(config_file)?;
+    let Some(directory) = config_file.parent() else {
+        anyhow::bail!("Can't watch '{}'", config_file.display())
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+    loop {
+        rerun(cli, &config_file);
+
+        loop {
+            if is_config_event(receiver.recv()?, &config_file) {
+                break;
+            }
+        }
+        while receiver.recv_timeout(DEBOUNCE).is_ok() {}
+    }
+}
+
+fn rerun(cli: &Cli, config_file: &Path) {
+    println!("--- {} ---", config_file.display());
+    let result = read_config_data(cli).and_then(|json_data| process_config(cli, &json_data));
+    if let Err(error) = result {
+        eprintln!("Error: {:#}", error);
+    }
+}
+
+fn is_config_event(event: notify::Result<Event>, config_file: &Path) -> bool {
+    match event {
+        Ok(event) => event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == config_file.file_name()),
+        Err(_) => false,
+    }
+}